@@ -129,6 +129,7 @@ pub enum Swap {
     Deriverse {
         side: Side,
         instr_id: u32,
+        remaining_accounts_info: Option<RemainingAccountsInfo>,
     },
 }
 #[derive(Clone, PartialEq, Eq, Debug)]
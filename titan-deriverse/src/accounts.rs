@@ -0,0 +1,195 @@
+//! Checks applied to program accounts before they are decoded. Every program account starts
+//! with its little-endian account-type tag.
+
+use std::{fmt, mem::size_of};
+
+use bytemuck::pod_read_unaligned;
+use solana_sdk::{account::Account, pubkey::Pubkey};
+
+use drv_models::state::token::TokenState;
+
+use crate::program_id;
+
+pub const TOKEN_PROGRAM_ID: Pubkey =
+    Pubkey::from_str_const("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
+pub const TOKEN_2022_PROGRAM_ID: Pubkey =
+    Pubkey::from_str_const("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+
+/// Reason an account was rejected, wrapped in the returned `anyhow::Error`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountError {
+    Missing {
+        address: Pubkey,
+    },
+    InvalidOwner {
+        address: Pubkey,
+        owner: Pubkey,
+    },
+    TooSmall {
+        address: Pubkey,
+        len: usize,
+        expected: usize,
+    },
+    InvalidTag {
+        address: Pubkey,
+        tag: u32,
+        expected: u32,
+    },
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::Missing { address } => write!(f, "Account {} is not provided", address),
+            AccountError::InvalidOwner { address, owner } => {
+                write!(f, "Account {} is owned by {}", address, owner)
+            }
+            AccountError::TooSmall {
+                address,
+                len,
+                expected,
+            } => write!(
+                f,
+                "Account {} holds {} bytes, at least {} expected",
+                address, len, expected
+            ),
+            AccountError::InvalidTag {
+                address,
+                tag,
+                expected,
+            } => write!(
+                f,
+                "Account {} has type {}, {} expected",
+                address, tag, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+/// Checks that `account` is owned by the program, holds at least `min_size` bytes and
+/// carries the `tag` account type
+pub fn validate_program_account(
+    address: &Pubkey,
+    account: &Account,
+    tag: u32,
+    min_size: usize,
+) -> Result<(), AccountError> {
+    if account.owner != program_id::id() {
+        return Err(AccountError::InvalidOwner {
+            address: *address,
+            owner: account.owner,
+        });
+    }
+
+    let len = account.data.len();
+    let expected = min_size.max(size_of::<u32>());
+    if len < expected {
+        return Err(AccountError::TooSmall {
+            address: *address,
+            len,
+            expected,
+        });
+    }
+
+    let account_tag = pod_read_unaligned::<u32>(&account.data[..size_of::<u32>()]);
+    if account_tag != tag {
+        return Err(AccountError::InvalidTag {
+            address: *address,
+            tag: account_tag,
+            expected: tag,
+        });
+    }
+
+    Ok(())
+}
+
+/// Mint accounts have to be owned by the token or the Token-2022 program
+pub fn validate_mint_account(address: &Pubkey, account: &Account) -> Result<(), AccountError> {
+    if account.owner != TOKEN_PROGRAM_ID && account.owner != TOKEN_2022_PROGRAM_ID {
+        return Err(AccountError::InvalidOwner {
+            address: *address,
+            owner: account.owner,
+        });
+    }
+
+    Ok(())
+}
+
+/// Disagreement between the instrument header and the token accounts it refers to, e.g. a
+/// stale token state that would otherwise invert the swap direction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarketMismatch {
+    /// The header names other mints than the ones the market was built for
+    HeaderMint { expected: Pubkey, found: Pubkey },
+    TokenStateMint {
+        token_state: Pubkey,
+        expected: Pubkey,
+        found: Pubkey,
+    },
+    TokenStateId {
+        token_state: Pubkey,
+        expected: u32,
+        found: u32,
+    },
+}
+
+impl fmt::Display for MarketMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MarketMismatch::HeaderMint { expected, found } => write!(
+                f,
+                "Instrument header mint is {}, market was built for {}",
+                found, expected
+            ),
+            MarketMismatch::TokenStateMint {
+                token_state,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Token state {} is for mint {}, instrument expects {}",
+                token_state, found, expected
+            ),
+            MarketMismatch::TokenStateId {
+                token_state,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Token state {} has id {}, instrument expects {}",
+                token_state, found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MarketMismatch {}
+
+/// Checks that the token state at `address` describes `mint` with the `token_id` the
+/// instrument header refers to
+pub fn check_token_state(
+    address: &Pubkey,
+    token_state: &TokenState,
+    mint: &Pubkey,
+    token_id: u32,
+) -> Result<(), MarketMismatch> {
+    if token_state.address != *mint {
+        return Err(MarketMismatch::TokenStateMint {
+            token_state: *address,
+            expected: *mint,
+            found: token_state.address,
+        });
+    }
+
+    if token_state.id != token_id {
+        return Err(MarketMismatch::TokenStateId {
+            token_state: *address,
+            expected: token_id,
+            found: token_state.id,
+        });
+    }
+
+    Ok(())
+}
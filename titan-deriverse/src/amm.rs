@@ -1,16 +1,20 @@
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Result, anyhow};
 use drv_models::{
     constants::trading_limitations::MAX_SUM,
     state::{instrument::InstrAccountHeader, types::OrderSide},
 };
 
+use crate::math::{mul_div, mul_div_ceil, sqrt_mul_div};
+
+/// Price reported when the curve can't quote, i.e. the reserves are drained
+pub const MAX_AMM_PX: i64 = i64::MAX >> 1;
+
 #[derive(Clone, Default, PartialEq, Debug)]
 pub struct DeriverseAmm {
     pub k: i128,
     pub a_tokens: i64,
     pub b_tokens: i64,
-    pub df: f64,
-    pub rdf: f64,
+    pub dec_factor: i64,
 }
 
 impl DeriverseAmm {
@@ -19,55 +23,105 @@ impl DeriverseAmm {
             k: instr_header.asset_tokens as i128 * instr_header.crncy_tokens as i128,
             a_tokens: instr_header.asset_tokens,
             b_tokens: instr_header.crncy_tokens,
-            df: instr_header.dec_factor as f64,
-            rdf: 1f64 / instr_header.dec_factor as f64,
+            dec_factor: instr_header.dec_factor,
         }
     }
 
     pub fn trade_sum(&self, a: i64, b: i64) -> Result<i64> {
-        let sum = (a as f64 * b as f64) * self.rdf;
+        mul_div(a as i128, b as i128, self.dec_factor as i128)
+            .filter(|sum| *sum as f64 <= MAX_SUM)
+            .map(|sum| sum as i64)
+            .ok_or(anyhow!("Arithmetic overflow"))
+    }
+
+    /// [`Self::trade_sum`] rounded up, the sum a taker pays for `a` at `b`
+    pub fn trade_sum_ceil(&self, a: i64, b: i64) -> Result<i64> {
+        mul_div_ceil(a as i128, b as i128, self.dec_factor as i128)
+            .filter(|sum| *sum as f64 <= MAX_SUM)
+            .map(|sum| sum as i64)
+            .ok_or(anyhow!("Arithmetic overflow"))
+    }
+
+    /// Inverse of [`Self::trade_sum`]: qty bought by `sum` at `price`, rounded down
+    pub fn trade_qty(&self, sum: i64, price: i64) -> Result<i64> {
+        mul_div(sum as i128, self.dec_factor as i128, price as i128)
+            .and_then(|qty| i64::try_from(qty).ok())
+            .ok_or(anyhow!("Arithmetic overflow"))
+    }
 
-        if sum.is_sign_negative() || sum.is_nan() || sum > MAX_SUM {
-            bail!("Arithmetic overflow")
+    /// Inverse of [`Self::trade_sum`]: qty needed to collect `sum` at `price`, rounded up
+    pub fn trade_qty_ceil(&self, sum: i64, price: i64) -> Result<i64> {
+        mul_div_ceil(sum as i128, self.dec_factor as i128, price as i128)
+            .and_then(|qty| i64::try_from(qty).ok())
+            .ok_or(anyhow!("Arithmetic overflow"))
+    }
+
+    /// Asset reserve at which the curve quotes `price`
+    fn tokens_at_px(&self, price: i64) -> Result<i64> {
+        if price <= 0 {
+            return Ok(i64::MAX);
         }
 
-        Ok(sum as i64)
+        sqrt_mul_div(self.k, self.dec_factor as i128, price as i128)
+            .and_then(|tokens| i64::try_from(tokens).ok())
+            .ok_or(anyhow!("Arithmetic overflow"))
+    }
+
+    /// Curve price for the given asset reserve
+    fn px_at_tokens(&self, tokens: i128) -> i64 {
+        if tokens <= 0 {
+            return MAX_AMM_PX;
+        }
+
+        tokens
+            .checked_mul(tokens)
+            .and_then(|square| mul_div(self.k, self.dec_factor as i128, square))
+            .map_or(MAX_AMM_PX, |px| px.min(MAX_AMM_PX as i128) as i64)
+    }
+
+    /// Current curve price, `None` when a reserve is drained
+    pub fn spot_px(&self) -> Option<i64> {
+        if self.a_tokens <= 0 || self.b_tokens <= 0 {
+            return None;
+        }
+
+        mul_div(
+            self.b_tokens as i128,
+            self.dec_factor as i128,
+            self.a_tokens as i128,
+        )
+        .map(|px| px.min(MAX_AMM_PX as i128) as i64)
     }
 
     pub fn get_amm_qty(&self, price: i64, side: OrderSide) -> Result<i64> {
+        let tokens = self.tokens_at_px(price)?;
+
         Ok(match side {
-            OrderSide::Bid => ((((self.k as f64 * self.df / price as f64).sqrt()) as i64)
-                .checked_sub(self.a_tokens))
-            .ok_or(anyhow!("Arithmetic overflow"))?
-            .max(0),
-            OrderSide::Ask => (self
-                .a_tokens
-                .checked_sub(((self.k as f64 * self.df / price as f64).sqrt()) as i64))
-            .ok_or(anyhow!("Arithmetic overflow"))?
-            .max(0),
-        })
+            OrderSide::Bid => tokens.checked_sub(self.a_tokens),
+            OrderSide::Ask => self.a_tokens.checked_sub(tokens),
+        }
+        .ok_or(anyhow!("Arithmetic overflow"))?
+        .max(0))
     }
 
     pub fn get_amm_px(&self, q: i64, side: OrderSide) -> Result<i64> {
         Ok(match side {
             OrderSide::Bid => {
-                let new_tokens = (self
+                let new_tokens = self
                     .a_tokens
                     .checked_add(q)
-                    .ok_or(anyhow!("Arithmetic overflow"))?)
-                    as i128;
-                (((self.k as f64) * self.df) / (new_tokens * new_tokens) as f64) as i64
+                    .ok_or(anyhow!("Arithmetic overflow"))?;
+                self.px_at_tokens(new_tokens as i128)
             }
             OrderSide::Ask => {
                 if q >= self.a_tokens {
-                    i64::MAX >> 1
+                    MAX_AMM_PX
                 } else {
-                    let new_tokens = (self
+                    let new_tokens = self
                         .a_tokens
                         .checked_sub(q)
-                        .ok_or(anyhow!("Arithmetic overflow"))?)
-                        as i128;
-                    (((self.k as f64) * self.df) / (new_tokens * new_tokens) as f64) as i64
+                        .ok_or(anyhow!("Arithmetic overflow"))?;
+                    self.px_at_tokens(new_tokens as i128)
                 }
             }
         })
@@ -99,15 +153,34 @@ impl DeriverseAmm {
         })
     }
 
+    /// Currency paid to the curve for buying `traded_qty`, [`Self::get_amm_sum`] of the ask
+    /// side rounded up
+    pub fn get_amm_sum_ceil(&self, traded_qty: i64) -> Result<i64> {
+        let new_tokens = self.a_tokens - traded_qty;
+        if new_tokens <= 0 {
+            return Ok(0);
+        }
+
+        let new_crncy = (self.k + new_tokens as i128 - 1) / new_tokens as i128;
+
+        Ok(new_crncy
+            .checked_sub(self.b_tokens as i128)
+            .ok_or(anyhow!("Arithmetic overflow"))?
+            .max(0) as i64)
+    }
+
     pub fn get_reversed_amm_px(&self, sum: i64) -> Result<i64> {
-        if self.b_tokens == 0 {
-            Ok(i64::MAX >> 1)
+        if self.b_tokens == 0 || self.k == 0 {
+            Ok(MAX_AMM_PX)
         } else {
             let new_crncy = (self
                 .b_tokens
                 .checked_add(sum)
                 .ok_or(anyhow!("Arithmetic overflow"))?) as i128;
-            Ok((((new_crncy * new_crncy) as f64 * self.df) / self.k as f64) as i64)
+            Ok(new_crncy
+                .checked_mul(new_crncy)
+                .and_then(|square| mul_div(square, self.dec_factor as i128, self.k))
+                .map_or(MAX_AMM_PX, |px| px.min(MAX_AMM_PX as i128) as i64))
         }
     }
 
@@ -123,36 +196,59 @@ impl DeriverseAmm {
         }
     }
 
-    pub fn get_reversed_amm_sum(&self, price: i64) -> Result<i64> {
-        if self.b_tokens == 0 {
-            Ok(0)
-        } else {
-            Ok(-((self
-                .b_tokens
-                .checked_sub(((self.k as f64 * price as f64 / self.df).sqrt()) as i64))
-            .ok_or(anyhow!("Arithmetic overflow"))?)
-            .max(0))
-        }
+    pub fn get_amm_qty_for_sum(&self, traded_sum: i64, side: OrderSide) -> Result<i64> {
+        Ok(match side {
+            OrderSide::Bid => {
+                let new_crncy = self
+                    .b_tokens
+                    .checked_sub(traded_sum)
+                    .ok_or(anyhow!("Arithmetic overflow"))?;
+                if self.a_tokens == 0 || new_crncy <= 0 {
+                    0
+                } else {
+                    let new_tokens = (self.k + new_crncy as i128 - 1) / new_crncy as i128;
+                    (new_tokens - self.a_tokens as i128).max(0) as i64
+                }
+            }
+            OrderSide::Ask => self.get_reversed_amm_qty(traded_sum)?,
+        })
     }
 
-    pub fn partial_fill(amm_px: i64, price: i64, side: OrderSide) -> bool {
-        match side {
-            OrderSide::Bid => amm_px < price,
-            OrderSide::Ask => amm_px > price,
-        }
-    }
+    pub fn apply_trade(
+        &mut self,
+        traded_qty: i64,
+        traded_mints: i64,
+        side: OrderSide,
+    ) -> Result<()> {
+        let (a_tokens, b_tokens) = match side {
+            OrderSide::Bid => (
+                self.a_tokens.checked_add(traded_qty),
+                self.b_tokens.checked_sub(traded_mints),
+            ),
+            OrderSide::Ask => (
+                self.a_tokens.checked_sub(traded_qty),
+                self.b_tokens.checked_add(traded_mints),
+            ),
+        };
 
-    pub fn last_line(amm_px: i64, line_px: i64, side: OrderSide) -> bool {
-        match side {
-            OrderSide::Bid => amm_px >= line_px,
-            OrderSide::Ask => amm_px <= line_px,
-        }
+        self.a_tokens = a_tokens.ok_or(anyhow!("Arithmetic Overflow"))?;
+        self.b_tokens = b_tokens.ok_or(anyhow!("Arithmetic Overflow"))?;
+
+        Ok(())
     }
 
-    pub fn cover_line(amm_px: i64, price: i64, line_px: i64, side: OrderSide) -> bool {
-        match side {
-            OrderSide::Bid => amm_px.max(price) <= line_px,
-            OrderSide::Ask => amm_px.min(price) >= line_px,
+    pub fn get_reversed_amm_sum(&self, price: i64) -> Result<i64> {
+        if self.b_tokens == 0 || price <= 0 {
+            Ok(0)
+        } else {
+            let new_crncy = sqrt_mul_div(self.k, price as i128, self.dec_factor as i128)
+                .and_then(|crncy| i64::try_from(crncy).ok())
+                .ok_or(anyhow!("Arithmetic overflow"))?;
+
+            Ok(new_crncy
+                .checked_sub(self.b_tokens)
+                .ok_or(anyhow!("Arithmetic overflow"))?
+                .max(0))
         }
     }
 
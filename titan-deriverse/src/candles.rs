@@ -0,0 +1,141 @@
+//! OHLCV series decoded from the `SPOT_1M_CANDLES`, `SPOT_15M_CANDLES` and `SPOT_DAY_CANDLES`
+//! accounts. The program overwrites the oldest slot once an account is full, so the slots
+//! form a ring buffer and are reordered by time on decoding.
+
+use std::mem::size_of;
+
+use anyhow::{Result, bail};
+use bytemuck::pod_read_unaligned;
+use drv_models::state::{
+    spots::{candles::Candle, spot_account_header::SPOT_TRADE_ACCOUNT_HEADER_SIZE},
+    types::account_type::{SPOT_1M_CANDLES, SPOT_15M_CANDLES, SPOT_DAY_CANDLES},
+};
+use rust_decimal::Decimal;
+use solana_sdk::{account::Account, pubkey::Pubkey};
+
+use crate::{helper::Helper, price::human_px};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CandleInterval {
+    Minute,
+    FifteenMinutes,
+    Day,
+}
+
+impl CandleInterval {
+    pub const ALL: [CandleInterval; 3] = [
+        CandleInterval::Minute,
+        CandleInterval::FifteenMinutes,
+        CandleInterval::Day,
+    ];
+
+    pub fn tag(&self) -> u32 {
+        match self {
+            CandleInterval::Minute => SPOT_1M_CANDLES,
+            CandleInterval::FifteenMinutes => SPOT_15M_CANDLES,
+            CandleInterval::Day => SPOT_DAY_CANDLES,
+        }
+    }
+
+    pub fn seconds(&self) -> u32 {
+        match self {
+            CandleInterval::Minute => 60,
+            CandleInterval::FifteenMinutes => 15 * 60,
+            CandleInterval::Day => 24 * 60 * 60,
+        }
+    }
+
+    /// Candles account of the instrument for this interval
+    pub fn account(&self, asset_token_id: u32, crncy_token_id: u32) -> Pubkey {
+        Pubkey::new_spot_acc(self.tag(), asset_token_id, crncy_token_id)
+    }
+}
+
+/// Single OHLCV bar, prices in currency per asset
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bar {
+    /// Unix timestamp of the bar open
+    pub time: u32,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    /// Traded asset qty
+    pub volume: i64,
+    /// Traded currency sum
+    pub turnover: i64,
+}
+
+impl From<&Candle> for Bar {
+    fn from(candle: &Candle) -> Self {
+        Bar {
+            time: candle.time,
+            open: human_px(candle.open),
+            high: human_px(candle.high),
+            low: human_px(candle.low),
+            close: human_px(candle.close),
+            volume: candle.qty,
+            turnover: candle.sum,
+        }
+    }
+}
+
+/// Bars of one candles account, oldest first
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candles {
+    pub interval: CandleInterval,
+    pub bars: Vec<Bar>,
+}
+
+impl Candles {
+    pub fn new(interval: CandleInterval, candles_acc: &Account) -> Result<Self> {
+        let slots = candles_acc
+            .data
+            .get(SPOT_TRADE_ACCOUNT_HEADER_SIZE..)
+            .unwrap_or_default()
+            .chunks_exact(size_of::<Candle>())
+            .map(pod_read_unaligned::<Candle>)
+            .collect::<Vec<_>>();
+
+        // Slot after the newest bar holds the oldest one once the ring has wrapped
+        let start = slots
+            .iter()
+            .enumerate()
+            .filter(|(_, candle)| candle.time != 0)
+            .max_by_key(|(_, candle)| candle.time)
+            .map_or(0, |(idx, _)| idx + 1);
+
+        let bars = slots[start..]
+            .iter()
+            .chain(&slots[..start])
+            .filter(|candle| candle.time != 0)
+            .map(Bar::from)
+            .collect::<Vec<_>>();
+
+        if let Some(bars) = bars.windows(2).find(|bars| bars[0].time >= bars[1].time) {
+            bail!(
+                "Candles are out of ring order: {} follows {}",
+                bars[1].time,
+                bars[0].time
+            )
+        }
+
+        Ok(Candles { interval, bars })
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Bar> {
+        self.bars.iter()
+    }
+
+    pub fn last(&self) -> Option<&Bar> {
+        self.bars.last()
+    }
+
+    /// Bars opened within `[from, to)`
+    pub fn range(&self, from: u32, to: u32) -> &[Bar] {
+        let begin = self.bars.partition_point(|bar| bar.time < from);
+        let end = self.bars.partition_point(|bar| bar.time < to);
+
+        &self.bars[begin..end.max(begin)]
+    }
+}
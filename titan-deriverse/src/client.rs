@@ -0,0 +1,111 @@
+//! Read side of a client's state on an instrument. `SPOT_CLIENT_INFOS` and
+//! `SPOT_CLIENT_INFOS2` hold one record per client at the same slot, the client is identified
+//! by the id stored in its primary account.
+
+use std::mem::size_of;
+
+use anyhow::{Result, anyhow};
+use bytemuck::{Pod, pod_read_unaligned};
+use drv_models::state::{
+    client_primary_account_header::ClientPrimaryAccountHeader,
+    spots::{
+        client_infos::{SpotClientInfo, SpotClientInfo2},
+        spot_account_header::SPOT_TRADE_ACCOUNT_HEADER_SIZE,
+    },
+    types::OrderSide,
+};
+use solana_sdk::account::Account;
+
+use crate::{
+    amm::DeriverseAmm,
+    orders::{OrdersBook, SpotOrder},
+};
+
+/// Client position on one instrument
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientInstrInfo {
+    pub client_id: u32,
+    /// Resting orders in price-time priority, bids first
+    pub open_orders: Vec<(OrderSide, SpotOrder)>,
+    /// Asset tokens locked by ask orders
+    pub locked_asset_tokens: i64,
+    /// Currency locked by bid orders
+    pub locked_crncy_tokens: i64,
+    /// Currency paid in fees on the instrument
+    pub fees_paid: i64,
+}
+
+impl ClientInstrInfo {
+    /// `None` when the client never traded on the instrument
+    pub fn new(
+        client_id: u32,
+        orders: &OrdersBook,
+        amm: &DeriverseAmm,
+        client_infos_acc: &Account,
+        client_infos2_acc: &Account,
+    ) -> Result<Option<Self>> {
+        let Some(slot) = records::<SpotClientInfo>(&client_infos_acc.data)
+            .position(|info| info.client == client_id)
+        else {
+            return Ok(None);
+        };
+
+        let info2 = records::<SpotClientInfo2>(&client_infos2_acc.data)
+            .nth(slot)
+            .ok_or(anyhow!(
+                "Client info {} is missing in SPOT_CLIENT_INFOS2",
+                slot
+            ))?;
+
+        let open_orders = orders
+            .client_orders(client_id)
+            .map(|(side, order)| (side, *order))
+            .collect::<Vec<_>>();
+
+        let mut locked_asset_tokens = 0_i64;
+        let mut locked_crncy_tokens = 0_i64;
+
+        for (side, order) in &open_orders {
+            match side {
+                OrderSide::Bid => {
+                    locked_crncy_tokens = locked_crncy_tokens
+                        .checked_add(amm.trade_sum(order.qty, order.price)?)
+                        .ok_or(anyhow!("Arithmetic Overflow"))?;
+                }
+                OrderSide::Ask => {
+                    locked_asset_tokens = locked_asset_tokens
+                        .checked_add(order.qty)
+                        .ok_or(anyhow!("Arithmetic Overflow"))?;
+                }
+            }
+        }
+
+        Ok(Some(ClientInstrInfo {
+            client_id,
+            open_orders,
+            locked_asset_tokens,
+            locked_crncy_tokens,
+            fees_paid: info2.fees,
+        }))
+    }
+}
+
+/// Client id stored in the client primary account
+pub fn client_id(client_primary_acc: &Account) -> Result<u32> {
+    primary_header(client_primary_acc).map(|header| header.id)
+}
+
+pub(crate) fn primary_header(client_primary_acc: &Account) -> Result<ClientPrimaryAccountHeader> {
+    client_primary_acc
+        .data
+        .get(..size_of::<ClientPrimaryAccountHeader>())
+        .map(pod_read_unaligned::<ClientPrimaryAccountHeader>)
+        .ok_or(anyhow!("Client primary account is too short"))
+}
+
+fn records<T: Pod>(data: &[u8]) -> impl Iterator<Item = T> + '_ {
+    data.get(SPOT_TRADE_ACCOUNT_HEADER_SIZE..)
+        .unwrap_or_default()
+        .chunks_exact(size_of::<T>())
+        .map(pod_read_unaligned::<T>)
+}
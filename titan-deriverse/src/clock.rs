@@ -0,0 +1,73 @@
+use std::{fmt, sync::atomic::Ordering};
+
+use jupiter_amm_interface::ClockRef;
+
+/// Slot and time of the cluster at some point
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClockStamp {
+    pub slot: u64,
+    pub unix_timestamp: i64,
+}
+
+/// Shared cluster clock handed over by the router, the values move without updating the market
+#[derive(Clone, Default)]
+pub struct MarketClock(ClockRef);
+
+impl MarketClock {
+    pub fn new(clock_ref: &ClockRef) -> Self {
+        MarketClock(clock_ref.clone())
+    }
+
+    pub fn now(&self) -> ClockStamp {
+        ClockStamp {
+            slot: self.0.slot.load(Ordering::Relaxed),
+            unix_timestamp: self.0.unix_timestamp.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Current epoch, transfer fee schedules switch on epoch boundaries
+    pub fn epoch(&self) -> u64 {
+        self.0.epoch.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Debug for MarketClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MarketClock").field(&self.now()).finish()
+    }
+}
+
+impl PartialEq for MarketClock {
+    fn eq(&self, other: &Self) -> bool {
+        self.now() == other.now()
+    }
+}
+
+/// Why the market state can't be trusted anymore
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StaleState {
+    /// No update went through since the market was built
+    NeverUpdated,
+    /// Last update is older than the configured age
+    Outdated { slots: u64, seconds: i64 },
+    /// No trade on the instrument for longer than the configured idle time
+    Frozen { idle_seconds: i64 },
+}
+
+impl fmt::Display for StaleState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StaleState::NeverUpdated => write!(f, "Market state was never updated"),
+            StaleState::Outdated { slots, seconds } => write!(
+                f,
+                "Market state is {} slots / {} seconds old",
+                slots, seconds
+            ),
+            StaleState::Frozen { idle_seconds } => {
+                write!(f, "Market has not traded for {} seconds", idle_seconds)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StaleState {}
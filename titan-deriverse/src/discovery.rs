@@ -0,0 +1,93 @@
+//! Enumerates the spot instruments of the program. Instrument headers are looked up with a
+//! `getProgramAccounts` request filtered on their account type, or picked out of a local dump
+//! of the program accounts.
+
+use std::mem::size_of;
+
+use drv_models::state::{instrument::InstrAccountHeader, types::account_type::INSTR};
+use jupiter_amm_interface::KeyedAccount;
+use solana_sdk::{account::Account, pubkey::Pubkey};
+
+use crate::{accounts::validate_program_account, helper::Helper};
+
+#[cfg(feature = "rpc")]
+use anyhow::Result;
+#[cfg(feature = "rpc")]
+use solana_client::{
+    rpc_client::RpcClient,
+    rpc_config::RpcProgramAccountsConfig,
+    rpc_filter::{Memcmp, RpcFilterType},
+};
+
+#[cfg(feature = "rpc")]
+use crate::program_id;
+
+/// Minimum size of an instrument account, the header may be followed by data of later
+/// program versions
+pub const INSTR_ACCOUNT_SIZE: usize = size_of::<InstrAccountHeader>();
+
+/// Instrument header at `address`, `None` when the account is not the header of the spot
+/// instrument it claims to be
+pub fn instrument_header(address: &Pubkey, account: &Account) -> Option<InstrAccountHeader> {
+    validate_program_account(address, account, INSTR, INSTR_ACCOUNT_SIZE).ok()?;
+
+    let header =
+        bytemuck::pod_read_unaligned::<InstrAccountHeader>(&account.data[..INSTR_ACCOUNT_SIZE]);
+
+    // Headers are only found at the address derived from their token ids
+    (Pubkey::new_spot_acc(INSTR, header.asset_token_id, header.crncy_token_id) == *address)
+        .then_some(header)
+}
+
+/// Keyed accounts of every instrument in `accounts`, ordered by asset and currency token id.
+/// Accounts that aren't instrument headers are skipped, so a whole program dump can be passed
+pub fn discover_instruments(
+    accounts: impl IntoIterator<Item = (Pubkey, Account)>,
+) -> Vec<KeyedAccount> {
+    let mut instruments = accounts
+        .into_iter()
+        .filter_map(|(key, account)| {
+            let header = instrument_header(&key, &account)?;
+
+            Some((
+                (header.asset_token_id, header.crncy_token_id),
+                KeyedAccount {
+                    key,
+                    account,
+                    params: None,
+                },
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    instruments.sort_by_key(|(token_ids, _)| *token_ids);
+
+    instruments
+        .into_iter()
+        .map(|(_, keyed_account)| keyed_account)
+        .collect()
+}
+
+/// `getProgramAccounts` filters matching the instrument headers. The size is not filtered on
+/// since it only matches exactly, short accounts are dropped by [`discover_instruments`]
+#[cfg(feature = "rpc")]
+pub fn instrument_filters() -> Vec<RpcFilterType> {
+    vec![RpcFilterType::Memcmp(Memcmp::new_raw_bytes(
+        0,
+        INSTR.to_le_bytes().to_vec(),
+    ))]
+}
+
+/// Every instrument of the program, ready for [`Amm::from_keyed_account`](jupiter_amm_interface::Amm::from_keyed_account)
+#[cfg(feature = "rpc")]
+pub fn fetch_instruments(rpc: &RpcClient) -> Result<Vec<KeyedAccount>> {
+    let accounts = rpc.get_program_accounts_with_config(
+        &program_id::id(),
+        RpcProgramAccountsConfig {
+            filters: Some(instrument_filters()),
+            ..Default::default()
+        },
+    )?;
+
+    Ok(discover_instruments(accounts))
+}
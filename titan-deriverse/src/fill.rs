@@ -0,0 +1,118 @@
+use std::fmt;
+
+use crate::{LimitedQuote, amm::DeriverseAmm, math::mul_div};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FillSource {
+    Amm,
+    /// Index of the price line in the lines account
+    Line(u32),
+}
+
+/// Part of a quote executed against a single liquidity source
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FillLeg {
+    pub source: FillSource,
+    /// Line price, or the average execution price for AMM legs
+    pub price: i64,
+    pub qty: i64,
+    pub sum: i64,
+    pub fee: i64,
+}
+
+/// Ordered fill legs collected while matching a quote
+#[derive(Clone, Debug, Default)]
+pub struct FillTrace {
+    pub legs: Vec<FillLeg>,
+    fee_rate: f64,
+    dec_factor: i64,
+}
+
+impl FillTrace {
+    pub fn new(fee_rate: f64, dec_factor: i64) -> Self {
+        FillTrace {
+            legs: vec![],
+            fee_rate,
+            dec_factor,
+        }
+    }
+
+    pub fn fee(&self, sum: i64) -> i64 {
+        (sum as f64 * self.fee_rate) as i64
+    }
+
+    /// [`Self::fee`] rounded up
+    pub fn fee_ceil(&self, sum: i64) -> i64 {
+        (sum as f64 * self.fee_rate).ceil() as i64
+    }
+
+    pub fn amm(&mut self, qty: i64, sum: i64, fee: i64) {
+        let price = mul_div(sum as i128, self.dec_factor as i128, qty as i128)
+            .map_or(0, |price| price.min(i64::MAX as i128) as i64);
+
+        self.push(FillSource::Amm, price, qty, sum, fee);
+    }
+
+    pub fn line(&mut self, idx: u32, price: i64, qty: i64, sum: i64, fee: i64) {
+        self.push(FillSource::Line(idx), price, qty, sum, fee);
+    }
+
+    fn push(&mut self, source: FillSource, price: i64, qty: i64, sum: i64, fee: i64) {
+        self.legs.push(FillLeg {
+            source,
+            price,
+            qty,
+            sum,
+            fee,
+        });
+    }
+}
+
+/// Quote together with the legs it was assembled from
+#[derive(Clone, Debug)]
+pub struct DetailedQuote {
+    pub quote: LimitedQuote,
+    pub legs: Vec<FillLeg>,
+    /// AMM reserves after the quoted swap
+    pub amm: DeriverseAmm,
+}
+
+/// Why matching stopped before the requested amount was filled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// Liquidity is left, but beyond the limit price
+    PriceLimit,
+    /// The book side and the AMM reserves are drained
+    LiquidityExhausted,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FillStatus {
+    Filled,
+    /// `unfilled` is in the units of the requested amount: input for ExactIn, output for
+    /// ExactOut
+    Partial {
+        unfilled: u64,
+        reason: StopReason,
+    },
+}
+
+/// Returned instead of a partial quote when the market rejects partial fills
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartialFillError {
+    pub unfilled: u64,
+    pub reason: StopReason,
+}
+
+impl fmt::Display for PartialFillError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.reason {
+            StopReason::PriceLimit => "price limit reached",
+            StopReason::LiquidityExhausted => "liquidity exhausted",
+        };
+
+        write!(f, "Partial fill, {} unfilled: {}", self.unfilled, reason)
+    }
+}
+
+impl std::error::Error for PartialFillError {}
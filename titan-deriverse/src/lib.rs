@@ -2,41 +2,76 @@ use anyhow::{Result, anyhow, bail};
 use bytemuck::{Pod, Zeroable};
 use drv_models::{
     constants::{
-        instructions::{DrvInstruction, SwapInstruction},
-        voting::FEE_RATE_STEP,
+        instructions::DrvInstruction, trading_limitations::MAX_PRICE, voting::FEE_RATE_STEP,
     },
     instruction_data::SwapData,
     new_types::instrument::InstrId,
     state::{
+        client_primary_account_header::ClientPrimaryAccountHeader,
         community_account_header::CommunityAccountHeader,
         instrument::InstrAccountHeader,
+        spots::spot_account_header::SPOT_TRADE_ACCOUNT_HEADER_SIZE,
         token::TokenState,
         types::{
             OrderSide,
             account_type::{
-                COMMUNITY, INSTR, ROOT, SPOT_1M_CANDLES, SPOT_15M_CANDLES, SPOT_ASK_ORDERS,
-                SPOT_ASKS_TREE, SPOT_BID_ORDERS, SPOT_BIDS_TREE, SPOT_CLIENT_INFOS,
-                SPOT_CLIENT_INFOS2, SPOT_DAY_CANDLES, SPOT_LINES,
+                CLIENT_PRIMARY, COMMUNITY, INSTR, ROOT, SPOT_1M_CANDLES, SPOT_15M_CANDLES,
+                SPOT_ASK_ORDERS, SPOT_ASKS_TREE, SPOT_BID_ORDERS, SPOT_BIDS_TREE,
+                SPOT_CLIENT_INFOS, SPOT_CLIENT_INFOS2, SPOT_DAY_CANDLES, SPOT_LINES, TOKEN,
             },
         },
     },
 };
 
 use jupiter_amm_interface::{
-    AccountMap, Amm, Quote, Side, Swap, SwapAndAccountMetas, SwapMode, SwapParams,
+    AccountMap, AccountsType, Amm, Quote, QuoteParams, RemainingAccountsInfo,
+    RemainingAccountsSlice, Side, Swap, SwapAndAccountMetas, SwapMode, SwapParams,
 };
 use rust_decimal::Decimal;
-use solana_sdk::{instruction::AccountMeta, pubkey::Pubkey};
+use solana_sdk::{account::Account, instruction::AccountMeta, pubkey::Pubkey};
 
 use crate::{
-    amm::DeriverseAmm, helper::Helper, instrument::OffChainInstrAccountHeader,
+    accounts::{
+        AccountError, MarketMismatch, check_token_state, validate_mint_account,
+        validate_program_account,
+    },
+    amm::DeriverseAmm,
+    candles::{CandleInterval, Candles},
+    client::ClientInstrInfo,
+    clock::{ClockStamp, MarketClock, StaleState},
+    fill::{DetailedQuote, FillSource, FillStatus, PartialFillError, StopReason},
+    helper::Helper,
+    instrument::OffChainInstrAccountHeader,
     lines_linked_list::OrderBook,
+    lookup_table::{LookupTable, USER_SWAP_ACCOUNTS},
+    matching::{Budget, Fill, MatchingEngine},
+    orders::OrdersBook,
+    params::{DeriverseParams, PartialFillMode, PriceLimit},
+    price::{DepthLevel, PriceImpact, human_px},
+    transfer_fee::TransferFees,
+    transfer_hook::{HookTransfer, TransferHook},
 };
 
+pub mod accounts;
 pub mod amm;
+pub mod candles;
+pub mod client;
+pub mod clock;
+pub mod discovery;
+pub mod fill;
 pub mod helper;
 pub mod instrument;
 pub mod lines_linked_list;
+pub mod lookup_table;
+pub mod matching;
+pub mod math;
+pub mod orders;
+pub mod params;
+pub mod portfolio;
+pub mod price;
+pub mod resolver;
+pub mod transfer_fee;
+pub mod transfer_hook;
 
 #[cfg(test)]
 pub mod custom_sdk;
@@ -110,7 +145,7 @@ impl ContextAccounts {
 }
 
 #[derive(Clone, Debug, PartialEq)]
-struct Deriverse {
+pub struct Deriverse {
     accounts_ctx: ContextAccounts,
     instr_header: Box<InstrAccountHeader>,
     a_token_state: TokenState,
@@ -120,35 +155,746 @@ struct Deriverse {
     fee_rate_factor: f64,
     a_program_id: Pubkey,
     b_program_id: Pubkey,
+    a_transfer_fees: TransferFees,
+    b_transfer_fees: TransferFees,
+    a_transfer_hook: Option<TransferHook>,
+    b_transfer_hook: Option<TransferHook>,
+    /// Loaded from the `lookupTable` param, `None` while the table doesn't exist
+    lookup_table: Option<LookupTable>,
+    params: DeriverseParams,
+    clock: MarketClock,
+    /// Cluster clock at the last successful update
+    last_update: Option<ClockStamp>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LimitedQuote {
+    pub quote: Quote,
+    /// Price the quote was not allowed to cross
+    pub limit_price: i64,
+    pub status: FillStatus,
+}
+
+impl LimitedQuote {
+    /// The requested amount was cut because the next liquidity lies beyond `limit_price`
+    pub fn truncated_by_limit(&self) -> bool {
+        matches!(
+            self.status,
+            FillStatus::Partial {
+                reason: StopReason::PriceLimit,
+                ..
+            }
+        )
+    }
 }
 
 pub trait AccountsHolder {
     fn from_account<T: Pod>(&self, account_addr: &Pubkey) -> Result<T>;
+
+    /// Account owned by the program with at least `min_size` bytes and the `tag` account type
+    fn program_account(&self, account_addr: &Pubkey, tag: u32, min_size: usize)
+    -> Result<&Account>;
+
+    /// [`Self::from_account`] of a validated [`Self::program_account`]
+    fn from_program_account<T: Pod>(&self, account_addr: &Pubkey, tag: u32) -> Result<T>;
 }
 
 impl AccountsHolder for AccountMap {
     fn from_account<T: Pod>(&self, account_addr: &Pubkey) -> Result<T> {
-        let acc = self
-            .get(account_addr)
-            .ok_or(anyhow!("Invalid provided address {}", account_addr))?;
+        let acc = self.get(account_addr).ok_or(AccountError::Missing {
+            address: *account_addr,
+        })?;
+
+        Ok(acc
+            .data
+            .get(..std::mem::size_of::<T>())
+            .map(bytemuck::pod_read_unaligned::<T>)
+            .ok_or(AccountError::TooSmall {
+                address: *account_addr,
+                len: acc.data.len(),
+                expected: std::mem::size_of::<T>(),
+            })?)
+    }
+
+    fn program_account(
+        &self,
+        account_addr: &Pubkey,
+        tag: u32,
+        min_size: usize,
+    ) -> Result<&Account> {
+        let acc = self.get(account_addr).ok_or(AccountError::Missing {
+            address: *account_addr,
+        })?;
 
-        Ok(*bytemuck::from_bytes(
-            &acc.data.as_slice()[0..std::mem::size_of::<T>()],
-        ))
+        validate_program_account(account_addr, acc, tag, min_size)?;
+
+        Ok(acc)
+    }
+
+    fn from_program_account<T: Pod>(&self, account_addr: &Pubkey, tag: u32) -> Result<T> {
+        self.program_account(account_addr, tag, std::mem::size_of::<T>())?;
+        self.from_account(account_addr)
+    }
+}
+
+impl Deriverse {
+    /// Quotes the swap with the given price limit, falls back to the market's configured
+    /// limit when none is provided. Partial fills are reported through the status whatever
+    /// the market's [`PartialFillMode`]
+    pub fn quote_with_limit(
+        &self,
+        quote_params: &QuoteParams,
+        price_limit: Option<PriceLimit>,
+    ) -> Result<LimitedQuote> {
+        self.quote_detailed(quote_params, price_limit)
+            .map(|detailed| detailed.quote)
+    }
+
+    /// Same as [`Self::quote_with_limit`], additionally returns every fill leg in execution
+    /// order and the AMM reserves after the swap
+    pub fn quote_detailed(
+        &self,
+        quote_params: &QuoteParams,
+        price_limit: Option<PriceLimit>,
+    ) -> Result<DetailedQuote> {
+        self.check_staleness()?;
+
+        let Deriverse {
+            instr_header,
+            b_token_state,
+            order_book,
+            params,
+            ..
+        } = self;
+
+        let buy = b_token_state.address == quote_params.input_mint;
+
+        let px = instr_header.market_px();
+        let price = price_limit.unwrap_or(params.price_limit).limit_px(px, buy);
+
+        let crossed = if buy {
+            price > px || order_book.cross(price, OrderSide::Ask)
+        } else {
+            price < px || order_book.cross(price, OrderSide::Bid)
+        };
+
+        if !crossed {
+            bail!("Swap failed")
+        }
+
+        let epoch = self.clock.epoch();
+        let (in_fees, out_fees) = self.transfer_fees(buy);
+
+        let amount = match quote_params.swap_mode {
+            SwapMode::ExactIn => in_fees.net(epoch, quote_params.amount)?,
+            SwapMode::ExactOut => out_fees.gross(epoch, quote_params.amount)?,
+        } as i64;
+
+        let fee_rate = self.fee_rate();
+
+        let budget = match (quote_params.swap_mode, buy) {
+            (SwapMode::ExactIn, true) => Budget::Sum(buy_budget(amount, fee_rate)),
+            (SwapMode::ExactIn, false) | (SwapMode::ExactOut, true) => Budget::Qty(amount),
+            // Sell fees are withheld from the received sum, so the gross sum has to cover them
+            (SwapMode::ExactOut, false) => {
+                Budget::Sum((amount as f64 / (1.0 - fee_rate)).ceil() as i64)
+            }
+        };
+
+        let mut engine = self.matching_engine(buy, price);
+        let fill = engine.fill(budget)?;
+
+        if fill.qty == 0 || fill.sum == 0 {
+            bail!("Swap failed")
+        }
+
+        let mut quote = if buy {
+            let mut fees = fill.fees;
+
+            // Sent back as an ExactIn amount, the input has to buy the same sum again, the f64
+            // division of its budget may come out a unit short
+            if quote_params.swap_mode == SwapMode::ExactOut {
+                while buy_budget(fill.sum + fees, fee_rate) < fill.sum {
+                    fees += 1;
+                }
+            }
+
+            let in_amount = fill.sum + fees;
+
+            Quote {
+                in_amount: in_amount as u64,
+                out_amount: fill.qty as u64,
+                fee_amount: fees as u64,
+                fee_mint: b_token_state.address,
+                fee_pct: Decimal::from(fees) / Decimal::from(in_amount),
+            }
+        } else {
+            let out_amount = fill.sum - fill.fees;
+
+            Quote {
+                in_amount: fill.qty as u64,
+                out_amount: out_amount as u64,
+                fee_amount: fill.fees as u64,
+                fee_mint: b_token_state.address,
+                fee_pct: Decimal::from(fill.fees) / Decimal::from(out_amount),
+            }
+        };
+
+        quote.in_amount = in_fees.gross(epoch, quote.in_amount)?;
+        quote.out_amount = out_fees.net(epoch, quote.out_amount)?;
+
+        let status = if fill.remaining > 0 {
+            let matched = match quote_params.swap_mode {
+                SwapMode::ExactIn => quote.in_amount,
+                SwapMode::ExactOut => quote.out_amount,
+            };
+
+            FillStatus::Partial {
+                unfilled: quote_params.amount.saturating_sub(matched),
+                reason: if engine.limit_reached()? {
+                    StopReason::PriceLimit
+                } else {
+                    StopReason::LiquidityExhausted
+                },
+            }
+        } else {
+            FillStatus::Filled
+        };
+
+        let (amm, legs) = engine.into_parts();
+
+        Ok(DetailedQuote {
+            quote: LimitedQuote {
+                quote,
+                limit_price: price,
+                status,
+            },
+            legs,
+            amm,
+        })
+    }
+
+    /// Quotes the swap and returns the market as it would be right after it: AMM reserves
+    /// moved, consumed lines reduced or removed and `last_px` set to the last fill. Partial
+    /// fills are handled like in [`Amm::quote`]
+    pub fn simulate_swap(&self, quote_params: &QuoteParams) -> Result<(Quote, Deriverse)> {
+        let detailed = self.quote_detailed(quote_params, None)?;
+        self.check_partial_fill(&detailed.quote)?;
+
+        let buy = self.b_token_state.address == quote_params.input_mint;
+
+        let mut market = self.clone();
+        let side = if buy { OrderSide::Ask } else { OrderSide::Bid };
+
+        market.order_book.apply_fills(side, &detailed.legs)?;
+        market.sync_book_header();
+
+        let header = market.instr_header.as_mut();
+        header.asset_tokens = detailed.amm.a_tokens;
+        header.crncy_tokens = detailed.amm.b_tokens;
+
+        // A leg without qty doesn't trade, the last price is set by the one before it
+        if let Some(leg) = detailed.legs.iter().rev().find(|leg| leg.qty > 0) {
+            header.last_px = match leg.source {
+                FillSource::Line(_) => leg.price,
+                FillSource::Amm => detailed.amm.spot_px().unwrap_or(leg.price),
+            };
+        }
+
+        market.amm = detailed.amm;
+
+        Ok((detailed.quote.quote, market))
+    }
+
+    /// `SPOT_BID_ORDERS` and `SPOT_ASK_ORDERS` accounts of the instrument
+    pub fn orders_accounts(&self) -> [Pubkey; 2] {
+        let InstrAccountHeader {
+            asset_token_id,
+            crncy_token_id,
+            ..
+        } = *self.instr_header;
+
+        [
+            Pubkey::new_spot_acc(SPOT_BID_ORDERS, asset_token_id, crncy_token_id),
+            Pubkey::new_spot_acc(SPOT_ASK_ORDERS, asset_token_id, crncy_token_id),
+        ]
+    }
+
+    /// Order-level book, `account_map` has to hold the [`Self::orders_accounts`]
+    pub fn orders_book(&self, account_map: &AccountMap) -> Result<OrdersBook> {
+        let [bid_orders, ask_orders] = self.orders_accounts();
+
+        let bid_orders_acc = account_map.program_account(
+            &bid_orders,
+            SPOT_BID_ORDERS,
+            SPOT_TRADE_ACCOUNT_HEADER_SIZE,
+        )?;
+        let ask_orders_acc = account_map.program_account(
+            &ask_orders,
+            SPOT_ASK_ORDERS,
+            SPOT_TRADE_ACCOUNT_HEADER_SIZE,
+        )?;
+
+        OrdersBook::new(&self.order_book, bid_orders_acc, ask_orders_acc)
+    }
+
+    /// Market without the client's resting orders, quoting on it never self-trades
+    pub fn without_client_orders(&self, orders: &OrdersBook, client_id: u32) -> Result<Deriverse> {
+        let mut market = self.clone();
+
+        market.order_book = orders.exclude_client(&self.order_book, client_id)?;
+        market.sync_book_header();
+
+        Ok(market)
+    }
+
+    /// `SPOT_CLIENT_INFOS` and `SPOT_CLIENT_INFOS2` accounts of the instrument
+    pub fn client_infos_accounts(&self) -> [Pubkey; 2] {
+        let InstrAccountHeader {
+            asset_token_id,
+            crncy_token_id,
+            ..
+        } = *self.instr_header;
+
+        [
+            Pubkey::new_spot_acc(SPOT_CLIENT_INFOS, asset_token_id, crncy_token_id),
+            Pubkey::new_spot_acc(SPOT_CLIENT_INFOS2, asset_token_id, crncy_token_id),
+        ]
+    }
+
+    /// Open orders, locked balances and fees of `wallet` on the instrument, `None` when it
+    /// never traded here. `account_map` has to hold the wallet's client primary account, the
+    /// [`Self::client_infos_accounts`] and the [`Self::orders_accounts`]
+    pub fn client_info(
+        &self,
+        wallet: &Pubkey,
+        account_map: &AccountMap,
+    ) -> Result<Option<ClientInstrInfo>> {
+        let client_primary = wallet.new_client_primary_acc();
+        let [client_infos, client_infos2] = self.client_infos_accounts();
+
+        let client_primary_acc = account_map.program_account(
+            &client_primary,
+            CLIENT_PRIMARY,
+            std::mem::size_of::<ClientPrimaryAccountHeader>(),
+        )?;
+        let client_infos_acc = account_map.program_account(
+            &client_infos,
+            SPOT_CLIENT_INFOS,
+            SPOT_TRADE_ACCOUNT_HEADER_SIZE,
+        )?;
+        let client_infos2_acc = account_map.program_account(
+            &client_infos2,
+            SPOT_CLIENT_INFOS2,
+            SPOT_TRADE_ACCOUNT_HEADER_SIZE,
+        )?;
+
+        ClientInstrInfo::new(
+            client::client_id(client_primary_acc)?,
+            &self.orders_book(account_map)?,
+            &self.amm,
+            client_infos_acc,
+            client_infos2_acc,
+        )
+    }
+
+    /// Cluster clock at the last successful [`Amm::update`]
+    pub fn last_update(&self) -> Option<ClockStamp> {
+        self.last_update
+    }
+
+    /// Reason the market state is too old to quote on, checked against the configured
+    /// [`MaxAge`](params::MaxAge)
+    pub fn staleness(&self) -> Option<StaleState> {
+        self.params.max_age.check(
+            self.clock.now(),
+            self.last_update,
+            self.instr_header.last_time as i64,
+        )
+    }
+
+    /// Swap accounts that don't depend on the user in swap order and without duplicates, the
+    /// contents of the instrument's lookup table
+    pub fn lookup_table_accounts(&self) -> Vec<Pubkey> {
+        let InstrAccountHeader {
+            asset_token_id,
+            crncy_token_id,
+            asset_mint,
+            crncy_mint,
+            maps_address,
+            ..
+        } = *self.instr_header;
+
+        let [bid_orders, ask_orders] = self.orders_accounts();
+        let [client_infos, client_infos2] = self.client_infos_accounts();
+
+        let swap_accounts = [
+            Pubkey::new_acc(ROOT),
+            self.accounts_ctx.instr_header,
+            Pubkey::new_spot_acc(SPOT_BIDS_TREE, asset_token_id, crncy_token_id),
+            Pubkey::new_spot_acc(SPOT_ASKS_TREE, asset_token_id, crncy_token_id),
+            bid_orders,
+            ask_orders,
+            self.accounts_ctx.lines,
+            maps_address,
+            client_infos,
+            client_infos2,
+            self.candles_account(CandleInterval::Minute),
+            self.candles_account(CandleInterval::FifteenMinutes),
+            self.candles_account(CandleInterval::Day),
+            self.accounts_ctx.community_acc,
+            self.a_token_state.program_address,
+            self.b_token_state.program_address,
+            asset_mint,
+            crncy_mint,
+            self.accounts_ctx.a_token_state_acc,
+            self.accounts_ctx.b_token_state_acc,
+            Pubkey::get_drv_auth(),
+            solana_sdk::system_program::id(),
+            self.a_program_id,
+            self.b_program_id,
+            spl_associated_token_account::id(),
+        ];
+
+        let hook_accounts = [&self.a_transfer_hook, &self.b_transfer_hook]
+            .into_iter()
+            .flatten()
+            .flat_map(|hook| [hook.program_id, hook.validation_account]);
+
+        let mut accounts = Vec::with_capacity(swap_accounts.len() + 4);
+
+        for account in swap_accounts.into_iter().chain(hook_accounts) {
+            if !accounts.contains(&account) {
+                accounts.push(account);
+            }
+        }
+
+        accounts
+    }
+
+    /// Candles account of the instrument for `interval`
+    pub fn candles_account(&self, interval: CandleInterval) -> Pubkey {
+        interval.account(
+            self.instr_header.asset_token_id,
+            self.instr_header.crncy_token_id,
+        )
+    }
+
+    /// OHLCV series, `account_map` has to hold the [`Self::candles_account`]
+    pub fn candles(&self, interval: CandleInterval, account_map: &AccountMap) -> Result<Candles> {
+        let candles = self.candles_account(interval);
+
+        let candles_acc = account_map.program_account(
+            &candles,
+            interval.tag(),
+            SPOT_TRADE_ACCOUNT_HEADER_SIZE,
+        )?;
+
+        Candles::new(interval, candles_acc)
+    }
+
+    /// AMM curve price in currency per asset, `None` when the pool is empty
+    pub fn spot_price(&self) -> Option<Decimal> {
+        self.amm.spot_px().map(human_px)
+    }
+
+    /// Best resting bid and ask in currency per asset
+    pub fn best_bid_ask(&self) -> (Option<Decimal>, Option<Decimal>) {
+        let best = |side| self.order_book.begin(side).map(|line| human_px(line.price));
+
+        (best(OrderSide::Bid), best(OrderSide::Ask))
+    }
+
+    /// Best ask minus best bid, negative when the book is crossed
+    pub fn spread(&self) -> Option<Decimal> {
+        match self.best_bid_ask() {
+            (Some(bid), Some(ask)) => Some(ask - bid),
+            _ => None,
+        }
+    }
+
+    /// Matches `amount` asset tokens against the book and the AMM, walking no further than
+    /// the price that fills it.
+    /// `side` is the taker side, `Bid` buys the asset and `Ask` sells it. The amount is the
+    /// one sent or received by the taker, prices are the matching ones.
+    pub fn price_impact(&self, amount: u64, side: OrderSide) -> Result<PriceImpact> {
+        self.check_staleness()?;
+
+        let buy = matches!(side, OrderSide::Bid);
+        let epoch = self.clock.epoch();
+        let (in_fees, out_fees) = self.transfer_fees(buy);
+
+        let amount = if buy {
+            out_fees.gross(epoch, amount)?
+        } else {
+            in_fees.net(epoch, amount)?
+        };
+
+        // The AMM alone fills the amount by this price, so the walk never needs lines past it.
+        // The curve price is rounded down, a buy goes one unit further to take the whole
+        // amount. Past the asset reserve a buy drains the AMM to its last token and every ask
+        let limit_px = if buy {
+            let reserve = self.amm.a_tokens - 1;
+
+            if (amount as i64) < reserve {
+                self.amm.get_amm_px(amount as i64, OrderSide::Ask)? + 1
+            } else {
+                let drained_px = self.amm.get_amm_px(reserve.max(0), OrderSide::Ask)?;
+
+                self.order_book
+                    .iter_asks()
+                    .map(|(_, line)| line.price)
+                    .fold(drained_px, i64::max)
+            }
+        } else {
+            self.amm.get_amm_px(amount as i64, OrderSide::Bid)?
+        };
+
+        let mut engine = self.matching_engine(buy, limit_px);
+
+        let spot_px = engine
+            .marginal_px()?
+            .filter(|px| *px > 0)
+            .ok_or(anyhow!("No liquidity"))?;
+
+        let fill = engine.fill(Budget::Qty(amount as i64))?;
+
+        if fill.qty == 0 {
+            bail!("No liquidity")
+        }
+
+        let qty = if buy {
+            out_fees.net(epoch, fill.qty as u64)?
+        } else {
+            in_fees.gross(epoch, fill.qty as u64)?
+        };
+
+        let avg_px = fill
+            .avg_px(self.amm.dec_factor)
+            .ok_or(anyhow!("Arithmetic overflow"))?;
+
+        let spot_price = human_px(spot_px);
+        let avg_price = human_px(avg_px);
+
+        Ok(PriceImpact {
+            qty: qty as i64,
+            spot_price,
+            avg_price,
+            marginal_price: engine.marginal_px()?.map(human_px),
+            impact: ((avg_price - spot_price) / spot_price).abs(),
+        })
+    }
+
+    /// Largest ExactIn input that fills completely before the price limit is reached or the
+    /// book and the AMM run dry, transfer fee of the input included. `side` is the taker
+    /// side, `Bid` spends currency for the asset.
+    pub fn max_fillable(&self, side: OrderSide, price_limit: Option<PriceLimit>) -> Result<u64> {
+        self.check_staleness()?;
+
+        let buy = matches!(side, OrderSide::Bid);
+        let price = self.limit_px(buy, price_limit);
+
+        // Unbounded budget, the engine stops on the limit or on exhausted liquidity
+        let fill = self.matching_engine(buy, price).fill(if buy {
+            Budget::Sum(i64::MAX)
+        } else {
+            Budget::Qty(i64::MAX)
+        })?;
+
+        let amount = if buy {
+            fill.sum
+                .checked_add(fill.fees)
+                .ok_or(anyhow!("Arithmetic Overflow"))?
+        } else {
+            fill.qty
+        };
+
+        let (in_fees, _) = self.transfer_fees(buy);
+        in_fees.gross(self.clock.epoch(), amount as u64)
+    }
+
+    /// Cumulative ExactIn depth for ascending input `amounts`, matched in a single pass up
+    /// to the price limit. `side` is the taker side, `Bid` spends currency for the asset.
+    /// The ladder ends early at the first level that can't be filled completely.
+    pub fn depth_ladder(
+        &self,
+        side: OrderSide,
+        amounts: &[u64],
+        price_limit: Option<PriceLimit>,
+    ) -> Result<Vec<DepthLevel>> {
+        if amounts.windows(2).any(|pair| pair[0] > pair[1]) {
+            bail!("Depth amounts must be ascending")
+        }
+
+        self.check_staleness()?;
+
+        let buy = matches!(side, OrderSide::Bid);
+        let price = self.limit_px(buy, price_limit);
+        let fee_rate = self.fee_rate();
+        let epoch = self.clock.epoch();
+        let (in_fees, out_fees) = self.transfer_fees(buy);
+
+        let mut engine = self.matching_engine(buy, price);
+        let mut total = Fill::default();
+        let mut matched = 0_i64;
+        let mut worst_px: Option<i64> = None;
+        let mut levels = Vec::with_capacity(amounts.len());
+
+        for &amount in amounts {
+            let amount_in = in_fees.net(epoch, amount)?;
+            let target = if buy {
+                buy_budget(amount_in as i64, fee_rate)
+            } else {
+                amount_in as i64
+            };
+
+            let legs_count = engine.legs().len();
+            let fill = engine.fill(if buy {
+                Budget::Sum(target - matched)
+            } else {
+                Budget::Qty(target - matched)
+            })?;
+            total.merge(&fill)?;
+
+            for leg in &engine.legs()[legs_count..] {
+                let leg_px = match leg.source {
+                    FillSource::Line(_) => Some(leg.price),
+                    FillSource::Amm => engine.amm().spot_px(),
+                };
+
+                worst_px = match (worst_px, leg_px) {
+                    (Some(worst), Some(leg_px)) => Some(if buy {
+                        worst.max(leg_px)
+                    } else {
+                        worst.min(leg_px)
+                    }),
+                    (px, None) | (None, px) => px,
+                };
+            }
+
+            let Some(avg_px) = total.avg_px(self.amm.dec_factor) else {
+                break;
+            };
+
+            let (in_amount, out_amount) = if buy {
+                (total.sum + total.fees, total.qty)
+            } else {
+                (total.qty, total.sum - total.fees)
+            };
+
+            levels.push(DepthLevel {
+                amount,
+                in_amount: in_fees.gross(epoch, in_amount as u64)?,
+                out_amount: out_fees.net(epoch, out_amount as u64)?,
+                avg_price: human_px(avg_px),
+                worst_price: human_px(worst_px.unwrap_or(avg_px)),
+            });
+
+            if fill.remaining > 0 {
+                break;
+            }
+
+            matched = target;
+        }
+
+        Ok(levels)
+    }
+
+    /// Fails when the market state is too old to quote on, see [`Self::staleness`]
+    fn check_staleness(&self) -> Result<()> {
+        match self.staleness() {
+            Some(stale) => bail!(stale),
+            None => Ok(()),
+        }
+    }
+
+    /// Transfer fees of the tokens sent in and sent out by the taker. Tokens sent in lose the
+    /// input mint's fee before reaching the market, tokens sent out lose the output mint's
+    /// one before reaching the taker
+    fn transfer_fees(&self, buy: bool) -> (&TransferFees, &TransferFees) {
+        if buy {
+            (&self.b_transfer_fees, &self.a_transfer_fees)
+        } else {
+            (&self.a_transfer_fees, &self.b_transfer_fees)
+        }
+    }
+
+    /// Fails a partial quote unless the market allows them, see [`PartialFillMode`]
+    fn check_partial_fill(&self, quote: &LimitedQuote) -> Result<()> {
+        match quote.status {
+            FillStatus::Partial { unfilled, reason }
+                if self.params.partial_fill == PartialFillMode::Reject =>
+            {
+                bail!(PartialFillError { unfilled, reason })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Explicit `price_limit` or the market's configured one, applied to the market price
+    fn limit_px(&self, buy: bool, price_limit: Option<PriceLimit>) -> i64 {
+        price_limit
+            .unwrap_or(self.params.price_limit)
+            .limit_px(self.instr_header.market_px(), buy)
+    }
+
+    /// Mirrors the local order book into the header fields quotes read
+    fn sync_book_header(&mut self) {
+        let Deriverse {
+            instr_header,
+            order_book,
+            ..
+        } = self;
+
+        instr_header.bid_lines_begin = order_book.bid_begin_line;
+        instr_header.ask_lines_begin = order_book.ask_begin_line;
+        instr_header.bid_lines_count = order_book.bid_lines_count as u32;
+        instr_header.ask_lines_count = order_book.ask_lines_count as u32;
+        instr_header.best_bid = order_book
+            .begin(OrderSide::Bid)
+            .map_or(0, |line| line.price);
+        instr_header.best_ask = order_book
+            .begin(OrderSide::Ask)
+            .map_or(MAX_PRICE, |line| line.price);
+    }
+
+    fn fee_rate(&self) -> f64 {
+        self.instr_header.day_volatility * self.fee_rate_factor
+    }
+
+    /// Engine consuming the asks when `buy`, the bids otherwise
+    fn matching_engine(&self, buy: bool, limit_px: i64) -> MatchingEngine<'_> {
+        let side = if buy { OrderSide::Ask } else { OrderSide::Bid };
+
+        MatchingEngine::new(
+            self.amm.clone(),
+            self.order_book.checked_iter(side),
+            side,
+            limit_px,
+            self.fee_rate(),
+        )
     }
 }
 
 impl Amm for Deriverse {
     fn from_keyed_account(
         keyed_account: &jupiter_amm_interface::KeyedAccount,
-        _: &jupiter_amm_interface::AmmContext,
+        amm_context: &jupiter_amm_interface::AmmContext,
     ) -> Result<Self>
     where
         Self: Sized,
     {
-        let instr_header = Box::new(*bytemuck::from_bytes::<InstrAccountHeader>(
-            &keyed_account.account.data.as_slice()[..std::mem::size_of::<InstrAccountHeader>()],
-        ));
+        let instr_header = keyed_account
+            .account
+            .data
+            .get(..std::mem::size_of::<InstrAccountHeader>())
+            .map(bytemuck::pod_read_unaligned::<InstrAccountHeader>)
+            .map(Box::new)
+            .ok_or(AccountError::TooSmall {
+                address: keyed_account.key,
+                len: keyed_account.account.data.len(),
+                expected: std::mem::size_of::<InstrAccountHeader>(),
+            })?;
 
         let accounts_ctx = ContextAccounts::build(instr_header.as_ref());
 
@@ -162,6 +908,14 @@ impl Amm for Deriverse {
             fee_rate_factor: 0.0,
             a_program_id: solana_sdk::system_program::id(),
             b_program_id: solana_sdk::system_program::id(),
+            a_transfer_fees: TransferFees::default(),
+            b_transfer_fees: TransferFees::default(),
+            a_transfer_hook: None,
+            b_transfer_hook: None,
+            lookup_table: None,
+            params: DeriverseParams::from_value(keyed_account.params.as_ref())?,
+            clock: MarketClock::new(&amm_context.clock_ref),
+            last_update: None,
         })
     }
 
@@ -177,8 +931,27 @@ impl Amm for Deriverse {
         self.accounts_ctx.instr_header
     }
 
+    /// Account keys a swap adds to the transaction, the ones loaded through the lookup table
+    /// only take an index and aren't counted
     fn get_accounts_len(&self) -> usize {
-        SwapInstruction::MIN_ACCOUNTS
+        let static_accounts = self.lookup_table_accounts();
+
+        let uncovered = match &self.lookup_table {
+            Some(table) => table.missing(&static_accounts).len(),
+            None => static_accounts.len(),
+        };
+
+        let hook_accounts: usize = [&self.a_transfer_hook, &self.b_transfer_hook]
+            .into_iter()
+            .flatten()
+            .map(TransferHook::extra_accounts_len)
+            .sum();
+
+        USER_SWAP_ACCOUNTS + uncovered + hook_accounts
+    }
+
+    fn supports_exact_out(&self) -> bool {
+        true
     }
 
     fn get_reserve_mints(&self) -> Vec<Pubkey> {
@@ -186,7 +959,22 @@ impl Amm for Deriverse {
     }
 
     fn get_accounts_to_update(&self) -> Vec<Pubkey> {
-        self.accounts_ctx.clone().into()
+        let mut accounts: Vec<Pubkey> = self.accounts_ctx.clone().into();
+
+        accounts.extend(
+            [&self.a_transfer_hook, &self.b_transfer_hook]
+                .into_iter()
+                .flatten()
+                .map(|hook| hook.validation_account),
+        );
+        accounts.extend(self.params.lookup_table);
+
+        accounts
+    }
+
+    fn has_dynamic_accounts(&self) -> bool {
+        // Transfer hook validation accounts are only known after the mints were loaded
+        true
     }
 
     fn update(&mut self, account_map: &jupiter_amm_interface::AccountMap) -> Result<()> {
@@ -200,31 +988,86 @@ impl Amm for Deriverse {
             b_mint,
         } = &self.accounts_ctx;
 
-        *self.instr_header = account_map.from_account(instr_header)?;
-        self.a_token_state = account_map.from_account(a_token_state_acc)?;
-        self.b_token_state = account_map.from_account(b_token_state_acc)?;
+        let header: InstrAccountHeader = account_map.from_program_account(instr_header, INSTR)?;
+        let a_token_state: TokenState =
+            account_map.from_program_account(a_token_state_acc, TOKEN)?;
+        let b_token_state: TokenState =
+            account_map.from_program_account(b_token_state_acc, TOKEN)?;
+
+        // Token accounts are derived from the mints the market was built with
+        for (expected, found) in [(a_mint, &header.asset_mint), (b_mint, &header.crncy_mint)] {
+            if expected != found {
+                bail!(MarketMismatch::HeaderMint {
+                    expected: *expected,
+                    found: *found,
+                })
+            }
+        }
 
-        self.fee_rate_factor = account_map
-            .from_account::<CommunityAccountHeader>(community_acc)?
+        check_token_state(
+            a_token_state_acc,
+            &a_token_state,
+            &header.asset_mint,
+            header.asset_token_id,
+        )?;
+        check_token_state(
+            b_token_state_acc,
+            &b_token_state,
+            &header.crncy_mint,
+            header.crncy_token_id,
+        )?;
+
+        let fee_rate_factor = account_map
+            .from_program_account::<CommunityAccountHeader>(community_acc, COMMUNITY)?
             .spot_fee_rate as f64
             * FEE_RATE_STEP;
 
-        let lines_acc = account_map
-            .get(lines)
-            .ok_or(anyhow!("Invalid lines account"))?;
-
-        self.order_book = OrderBook::new(&self.instr_header, lines_acc);
-        self.amm = DeriverseAmm::new(&self.instr_header);
+        let lines_acc =
+            account_map.program_account(lines, SPOT_LINES, SPOT_TRADE_ACCOUNT_HEADER_SIZE)?;
 
         let a_mint_acc = account_map
             .get(a_mint)
-            .ok_or(anyhow!("Invalid provided address {}", a_mint))?;
-        self.a_program_id = a_mint_acc.owner;
+            .ok_or(AccountError::Missing { address: *a_mint })?;
+        validate_mint_account(a_mint, a_mint_acc)?;
 
         let b_mint_acc = account_map
             .get(b_mint)
-            .ok_or(anyhow!("Invalid provided address {}", b_mint))?;
+            .ok_or(AccountError::Missing { address: *b_mint })?;
+        validate_mint_account(b_mint, b_mint_acc)?;
+
+        let a_transfer_fees = TransferFees::new(a_mint_acc)?;
+        let b_transfer_fees = TransferFees::new(b_mint_acc)?;
+
+        // Validation accounts are requested once the hook programs are known
+        let a_transfer_hook = TransferHook::from_accounts(a_mint, a_mint_acc, account_map)?;
+        let b_transfer_hook = TransferHook::from_accounts(b_mint, b_mint_acc, account_map)?;
+
+        // A table not created yet only makes the swaps bigger
+        let lookup_table = self
+            .params
+            .lookup_table
+            .and_then(|address| {
+                account_map
+                    .get(&address)
+                    .map(|table_acc| LookupTable::new(&address, table_acc))
+            })
+            .transpose()?;
+
+        // Nothing is applied until every account checked out
+        *self.instr_header = header;
+        self.a_token_state = a_token_state;
+        self.b_token_state = b_token_state;
+        self.fee_rate_factor = fee_rate_factor;
+        self.order_book = OrderBook::new(&self.instr_header, lines_acc);
+        self.amm = DeriverseAmm::new(&self.instr_header);
+        self.a_program_id = a_mint_acc.owner;
         self.b_program_id = b_mint_acc.owner;
+        self.a_transfer_fees = a_transfer_fees;
+        self.b_transfer_fees = b_transfer_fees;
+        self.a_transfer_hook = a_transfer_hook;
+        self.b_transfer_hook = b_transfer_hook;
+        self.lookup_table = lookup_table;
+        self.last_update = Some(self.clock.now());
 
         Ok(())
     }
@@ -233,481 +1076,10 @@ impl Amm for Deriverse {
         &self,
         quote_params: &jupiter_amm_interface::QuoteParams,
     ) -> Result<jupiter_amm_interface::Quote> {
-        let Deriverse {
-            instr_header,
-            b_token_state,
-            order_book,
-            amm,
-            fee_rate_factor,
-            ..
-        } = self;
-
-        let mut amm = amm.clone();
-
-        // reversed swap
-        if quote_params.swap_mode == SwapMode::ExactOut {
-            bail!("Exact out is not supported")
-        }
-
-        let buy = b_token_state.address == quote_params.input_mint;
-
-        let px = instr_header.market_px();
-        let price = {
-            let max_diff = px >> 3;
-
-            if buy { px + max_diff } else { px - max_diff }
-        };
-
-        let fee_rate = instr_header.day_volatility * fee_rate_factor;
-
-        let mut client_tokens: i64 = 0;
-        let mut client_mints: i64 = 0;
-        let mut fees_amount: i64 = 0;
-
-        if buy && (price > px || order_book.cross(price, OrderSide::Ask)) {
-            let input_sum = (quote_params.amount as f64 / (1.0 + fee_rate)) as i64;
-            let mut remaining_sum = input_sum;
-            let mut qty = 0_i64;
-            let mut total_fees = 0_i64;
-            let mut amm_px;
-            let traded_qty;
-            let traded_mints;
-            let mut next_amm_px;
-
-            let mut lines = order_book.iter_asks();
-
-            loop {
-                let line = lines.next();
-
-                amm_px = amm.get_reversed_amm_px(remaining_sum)?;
-
-                if line.is_none() {
-                    if DeriverseAmm::partial_fill(amm_px, price, OrderSide::Ask) {
-                        traded_qty = amm.get_amm_qty(price, OrderSide::Ask)?;
-                        traded_mints = amm.get_amm_sum(traded_qty, OrderSide::Ask)?;
-                        if traded_qty == 0 || traded_mints == 0 {
-                            break;
-                        }
-                    } else {
-                        traded_qty = amm.get_reversed_amm_qty(remaining_sum)?;
-                        if traded_qty == 0 {
-                            break;
-                        }
-                        traded_mints = remaining_sum;
-                    }
-                    remaining_sum -= traded_mints;
-
-                    qty = qty
-                        .checked_add(traded_qty)
-                        .ok_or(anyhow!("Arithmetic Overflow"))?;
-                    amm.a_tokens = amm
-                        .a_tokens
-                        .checked_sub(traded_qty)
-                        .ok_or(anyhow!("Arithmetic Overflow"))?;
-                    amm.b_tokens = amm
-                        .b_tokens
-                        .checked_add(traded_mints)
-                        .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                    total_fees = total_fees
-                        .checked_add((traded_mints as f64 * fee_rate) as i64)
-                        .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                    break;
-                }
+        let limited = self.quote_with_limit(quote_params, None)?;
+        self.check_partial_fill(&limited)?;
 
-                if let Some((_, line)) = line {
-                    let line_sum = amm.trade_sum(line.qty, line.price)?;
-
-                    // Proff of assumption - remaining_qty <= line_qty if remaining_sum <= line_sum
-                    // remaining_qty =
-                    //     remaining_sum * amm.df / line.price;
-                    //
-                    // line_sum = line_qty * line_price / amm.df
-                    // line_qty = line_sum * amm.df / line.price
-
-                    if remaining_sum <= line_sum {
-                        if DeriverseAmm::last_line(amm_px, line.price, OrderSide::Ask) {
-                            if DeriverseAmm::partial_fill(amm_px, price, OrderSide::Ask) {
-                                traded_qty = amm.get_amm_qty(price, OrderSide::Ask)?;
-                                traded_mints = amm.get_amm_sum(traded_qty, OrderSide::Ask)?;
-                                if traded_qty == 0 || traded_mints == 0 {
-                                    break;
-                                }
-                            } else {
-                                traded_qty = amm.get_reversed_amm_qty(remaining_sum)?;
-                                if traded_qty == 0 {
-                                    break;
-                                }
-                                traded_mints = remaining_sum;
-                            }
-
-                            remaining_sum -= traded_mints;
-                            qty = qty
-                                .checked_add(traded_qty)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                            amm.a_tokens = amm
-                                .a_tokens
-                                .checked_sub(traded_qty)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-                            amm.b_tokens = amm
-                                .b_tokens
-                                .checked_add(traded_mints)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-                        } else if DeriverseAmm::line_is_unreachable(
-                            price,
-                            line.price,
-                            OrderSide::Ask,
-                        ) {
-                            traded_qty = amm.get_amm_qty(price, OrderSide::Ask)?;
-                            traded_mints = amm.get_amm_sum(traded_qty, OrderSide::Ask)?;
-                            if traded_qty == 0 || traded_mints == 0 {
-                                break;
-                            }
-
-                            remaining_sum -= traded_mints;
-                            qty = qty
-                                .checked_add(traded_qty)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                            amm.a_tokens = amm
-                                .a_tokens
-                                .checked_sub(traded_qty)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-                            amm.b_tokens = amm
-                                .b_tokens
-                                .checked_add(traded_mints)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-                        } else {
-                            traded_qty = amm.get_amm_qty(line.price, OrderSide::Ask)?;
-                            traded_mints = amm.get_amm_sum(traded_qty, OrderSide::Ask)?;
-                            if traded_qty != 0 && traded_mints != 0 {
-                                remaining_sum -= traded_mints;
-                                qty = qty
-                                    .checked_add(traded_qty)
-                                    .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                                amm.a_tokens = amm
-                                    .a_tokens
-                                    .checked_sub(traded_qty)
-                                    .ok_or(anyhow!("Arithmetic Overflow"))?;
-                                amm.b_tokens = amm
-                                    .b_tokens
-                                    .checked_add(traded_mints)
-                                    .ok_or(anyhow!("Arithmetic Overflow"))?;
-                            }
-                            if remaining_sum > 0 {
-                                let fill_qty =
-                                    (remaining_sum as f64 * amm.df / line.price as f64) as i64;
-
-                                qty = qty
-                                    .checked_add(fill_qty)
-                                    .ok_or(anyhow!("Arithmetic Overflow"))?;
-                                total_fees = total_fees
-                                    .checked_add((remaining_sum as f64 * fee_rate) as i64)
-                                    .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                                remaining_sum = 0;
-                            }
-                        }
-                        if traded_qty != 0 && traded_mints != 0 {
-                            total_fees = total_fees
-                                .checked_add((traded_mints as f64 * fee_rate) as i64)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-                        }
-
-                        break;
-                    }
-
-                    next_amm_px = amm.get_reversed_amm_px(remaining_sum - line_sum)?;
-                    if DeriverseAmm::cover_line(next_amm_px, price, line.price, OrderSide::Ask) {
-                        qty = qty
-                            .checked_add(line.qty)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                        total_fees = total_fees
-                            .checked_add((line_sum as f64 * fee_rate) as i64)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                        remaining_sum -= line_sum;
-                        continue;
-                    }
-
-                    traded_mints = amm
-                        .get_reversed_amm_sum(line.price.min(price))?
-                        .min(remaining_sum);
-
-                    traded_qty = amm.get_reversed_amm_qty(traded_mints)?;
-
-                    if traded_qty != 0 && traded_mints != 0 {
-                        remaining_sum -= traded_mints;
-                        qty = qty
-                            .checked_add(traded_qty)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                        amm.a_tokens = amm
-                            .a_tokens
-                            .checked_sub(traded_qty)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-                        amm.b_tokens = amm
-                            .b_tokens
-                            .checked_add(traded_mints)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                        total_fees = total_fees
-                            .checked_add((traded_mints as f64 * fee_rate) as i64)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-                    }
-
-                    if DeriverseAmm::cover_line(amm_px, price, line.price, OrderSide::Ask) {
-                        qty = qty
-                            .checked_add(line.qty)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                        total_fees = total_fees
-                            .checked_add((line_sum as f64 * fee_rate) as i64)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                        remaining_sum -= line_sum;
-                    }
-
-                    break;
-                }
-            }
-
-            client_tokens += qty;
-            client_mints -= quote_params.amount as i64 - remaining_sum;
-
-            client_mints -= total_fees;
-            fees_amount = total_fees;
-        } else if !buy && (price < px || order_book.cross(price, OrderSide::Bid)) {
-            let mut remaining_qty = quote_params.amount as i64;
-            let mut sum = 0_i64;
-            let mut total_fees = 0_i64;
-            let mut amm_px;
-            let traded_qty;
-            let traded_mints;
-            let mut next_amm_px;
-
-            let mut lines = order_book.iter_bids();
-
-            loop {
-                let line = lines.next();
-
-                amm_px = amm.get_amm_px(remaining_qty, OrderSide::Bid)?;
-
-                if line.is_none() {
-                    if DeriverseAmm::partial_fill(amm_px, price, OrderSide::Bid) {
-                        traded_qty = amm.get_amm_qty(price, OrderSide::Bid)?;
-                        traded_mints = amm.get_amm_sum(traded_qty, OrderSide::Bid)?;
-                        if traded_qty == 0 || traded_mints == 0 {
-                            break;
-                        }
-                    } else {
-                        traded_mints = amm.get_amm_sum(remaining_qty, OrderSide::Bid)?;
-                        if traded_mints == 0 {
-                            break;
-                        }
-                        traded_qty = remaining_qty;
-                    }
-
-                    remaining_qty -= traded_qty;
-                    sum = sum
-                        .checked_add(traded_mints)
-                        .ok_or(anyhow!("Arithmetic Overflow"))?;
-                    amm.a_tokens = amm
-                        .a_tokens
-                        .checked_add(traded_qty)
-                        .ok_or(anyhow!("Arithmetic Overflow"))?;
-                    amm.b_tokens = amm
-                        .b_tokens
-                        .checked_sub(traded_mints)
-                        .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                    total_fees = total_fees
-                        .checked_add((traded_mints as f64 * fee_rate) as i64)
-                        .ok_or(anyhow!("Arithmetic Overflow"))?;
-                    break;
-                }
-
-                if let Some((_, line)) = line {
-                    if remaining_qty <= line.qty {
-                        if DeriverseAmm::last_line(amm_px, line.price, OrderSide::Bid) {
-                            if DeriverseAmm::partial_fill(amm_px, price, OrderSide::Bid) {
-                                traded_qty = amm.get_amm_qty(price, OrderSide::Bid)?;
-                                traded_mints = amm.get_amm_sum(traded_qty, OrderSide::Bid)?;
-                                if traded_qty == 0 || traded_mints == 0 {
-                                    break;
-                                }
-                            } else {
-                                traded_mints = amm.get_amm_sum(remaining_qty, OrderSide::Bid)?;
-                                if traded_mints == 0 {
-                                    break;
-                                }
-                                traded_qty = remaining_qty;
-                            }
-
-                            remaining_qty -= traded_qty;
-                            sum = sum
-                                .checked_add(traded_mints)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-                            amm.a_tokens = amm
-                                .a_tokens
-                                .checked_add(traded_qty)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-                            amm.b_tokens = amm
-                                .b_tokens
-                                .checked_sub(traded_mints)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-                        } else if DeriverseAmm::line_is_unreachable(
-                            price,
-                            line.price,
-                            OrderSide::Bid,
-                        ) {
-                            traded_qty = amm.get_amm_qty(price, OrderSide::Bid)?;
-                            traded_mints = amm.get_amm_sum(traded_qty, OrderSide::Bid)?;
-                            if traded_qty == 0 || traded_mints == 0 {
-                                break;
-                            }
-                            remaining_qty -= traded_qty;
-                            sum = sum
-                                .checked_add(traded_mints)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-                            amm.a_tokens = amm
-                                .a_tokens
-                                .checked_add(traded_qty)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-                            amm.b_tokens = amm
-                                .b_tokens
-                                .checked_sub(traded_mints)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-                        } else {
-                            traded_qty = amm.get_amm_qty(line.price, OrderSide::Bid)?;
-                            traded_mints = amm.get_amm_sum(traded_qty, OrderSide::Bid)?;
-
-                            if traded_qty != 0 && traded_mints != 0 {
-                                remaining_qty -= traded_qty;
-                                sum = sum
-                                    .checked_add(traded_mints)
-                                    .ok_or(anyhow!("Arithmetic Overflow"))?;
-                                amm.a_tokens = amm
-                                    .a_tokens
-                                    .checked_add(traded_qty)
-                                    .ok_or(anyhow!("Arithmetic Overflow"))?;
-                                amm.b_tokens = amm
-                                    .b_tokens
-                                    .checked_sub(traded_mints)
-                                    .ok_or(anyhow!("Arithmetic Overflow"))?;
-                            }
-
-                            if remaining_qty > 0 {
-                                // fill
-                                let fill_sum = amm.trade_sum(remaining_qty, line.price)?;
-                                total_fees = total_fees
-                                    .checked_add((fill_sum as f64 * fee_rate) as i64)
-                                    .ok_or(anyhow!("Arithmetic Overflow"))?;
-                                sum = sum
-                                    .checked_add(fill_sum)
-                                    .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                                remaining_qty = 0;
-                            }
-                        }
-
-                        if traded_mints != 0 && traded_qty != 0 {
-                            total_fees = total_fees
-                                .checked_add((traded_mints as f64 * fee_rate) as i64)
-                                .ok_or(anyhow!("Arithmetic Overflow"))?;
-                        }
-                        break;
-                    }
-
-                    next_amm_px = amm.get_amm_px(remaining_qty - line.qty, OrderSide::Bid)?;
-
-                    if DeriverseAmm::cover_line(next_amm_px, price, line.price, OrderSide::Bid) {
-                        let fill_sum = amm.trade_sum(line.qty, line.price)?;
-
-                        total_fees = total_fees
-                            .checked_add((fill_sum as f64 * fee_rate) as i64)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                        remaining_qty -= line.qty;
-                        sum = sum
-                            .checked_add(fill_sum)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                        continue;
-                    }
-
-                    traded_qty = amm
-                        .get_amm_qty(line.price.max(price), OrderSide::Bid)?
-                        .min(remaining_qty);
-                    traded_mints = amm.get_amm_sum(traded_qty, OrderSide::Bid)?;
-
-                    if traded_qty != 0 && traded_mints != 0 {
-                        remaining_qty -= traded_qty;
-                        sum = sum
-                            .checked_add(traded_mints)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-                        amm.a_tokens = amm
-                            .a_tokens
-                            .checked_add(traded_qty)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-                        amm.b_tokens = amm
-                            .b_tokens
-                            .checked_sub(traded_mints)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                        total_fees = total_fees
-                            .checked_add((traded_mints as f64 * fee_rate) as i64)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-                    }
-
-                    if DeriverseAmm::cover_line(next_amm_px, price, line.price, OrderSide::Bid) {
-                        let fill_sum = amm.trade_sum(line.qty, line.price)?;
-
-                        total_fees = total_fees
-                            .checked_add((fill_sum as f64 * fee_rate) as i64)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-
-                        remaining_qty -= line.qty;
-                        sum = sum
-                            .checked_add(fill_sum)
-                            .ok_or(anyhow!("Arithmetic Overflow"))?;
-                    }
-                }
-
-                break;
-            }
-            client_tokens -= quote_params.amount as i64 - remaining_qty;
-            client_mints += sum;
-
-            client_mints -= total_fees;
-            fees_amount = total_fees;
-        }
-
-        if client_tokens == 0 || client_mints == 0 {
-            bail!("Swap failed")
-        }
-
-        if buy {
-            Ok(Quote {
-                in_amount: (-1 * client_mints) as u64,
-                out_amount: client_tokens as u64,
-                fee_amount: fees_amount as u64,
-                fee_mint: b_token_state.address,
-                fee_pct: Decimal::from(fees_amount) / Decimal::from(-1 * client_mints),
-            })
-        } else {
-            Ok(Quote {
-                in_amount: (-1 * client_tokens) as u64,
-                out_amount: client_mints as u64,
-                fee_amount: fees_amount as u64,
-                fee_mint: b_token_state.address,
-                fee_pct: Decimal::from(fees_amount) / Decimal::from(client_mints),
-            })
-        }
+        Ok(limited.quote)
     }
 
     fn get_swap_and_account_metas(
@@ -721,10 +1093,14 @@ impl Amm for Deriverse {
             b_token_state,
             a_program_id,
             b_program_id,
+            a_transfer_hook,
+            b_transfer_hook,
             ..
         } = self;
 
         let SwapParams {
+            in_amount,
+            out_amount,
             destination_mint,
             source_mint,
             source_token_account,
@@ -752,7 +1128,7 @@ impl Amm for Deriverse {
 
         let root = Pubkey::new_acc(ROOT);
 
-        let account_metas = vec![
+        let mut account_metas = vec![
             AccountMeta {
                 pubkey: *token_transfer_authority,
                 is_signer: true,
@@ -935,10 +1311,61 @@ impl Amm for Deriverse {
             },
         ];
 
+        // The user pays into the market vault, the market pays out of it on behalf of the
+        // drv authority
+        let drv_auth = Pubkey::get_drv_auth();
+        let (a_transfer, b_transfer) = {
+            let pay_in = |account: &Pubkey, vault: Pubkey| HookTransfer {
+                source: *account,
+                destination: vault,
+                authority: *token_transfer_authority,
+                amount: *in_amount,
+            };
+            let pay_out = |account: &Pubkey, vault: Pubkey| HookTransfer {
+                source: vault,
+                destination: *account,
+                authority: drv_auth,
+                amount: *out_amount,
+            };
+
+            match side {
+                Side::Bid => (
+                    pay_out(a_account, a_token_state.program_address),
+                    pay_in(b_account, b_token_state.program_address),
+                ),
+                Side::Ask => (
+                    pay_in(a_account, a_token_state.program_address),
+                    pay_out(b_account, b_token_state.program_address),
+                ),
+            }
+        };
+
+        let mut slices = vec![];
+
+        for (hook, transfer, accounts_type) in [
+            (a_transfer_hook, a_transfer, AccountsType::TransferHookA),
+            (b_transfer_hook, b_transfer, AccountsType::TransferHookB),
+        ] {
+            let Some(hook) = hook else {
+                continue;
+            };
+
+            let hook_metas = hook.account_metas(&transfer)?;
+
+            slices.push(RemainingAccountsSlice {
+                accounts_type,
+                length: u8::try_from(hook_metas.len())
+                    .map_err(|_| anyhow!("Too many transfer hook accounts"))?,
+            });
+            account_metas.extend(hook_metas);
+        }
+
         Ok(SwapAndAccountMetas {
             swap: Swap::Deriverse {
                 side,
                 instr_id: *instr_header.instr_id,
+                remaining_accounts_info: (!slices.is_empty())
+                    .then_some(RemainingAccountsInfo { slices }),
             },
             account_metas,
         })
@@ -952,12 +1379,19 @@ impl Amm for Deriverse {
     }
 
     fn is_active(&self) -> bool {
-        self.order_book.total_lines_count != 0 && self.instr_header.ps != 0
+        self.order_book.total_lines_count != 0
+            && self.instr_header.ps != 0
+            && self.staleness().is_none()
     }
 }
 
+/// Sum an ExactIn buy of `amount` spends on the book and the AMM, the rest pays the fees
+fn buy_budget(amount: i64, fee_rate: f64) -> i64 {
+    (amount as f64 / (1.0 + fee_rate)) as i64
+}
+
 fn from_swap(swap: Swap, in_amount: u64) -> SwapData {
-    if let Swap::Deriverse { side, instr_id } = swap {
+    if let Swap::Deriverse { side, instr_id, .. } = swap {
         SwapData {
             tag: 26,
             input_crncy: (side == Side::Bid) as u8,
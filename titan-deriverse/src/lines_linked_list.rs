@@ -1,6 +1,7 @@
-use std::{fmt::Debug, marker::PhantomData};
+use std::{fmt::Debug, mem::size_of, sync::Arc};
 
-use bytemuck::cast_slice;
+use anyhow::{Result, anyhow, bail};
+use bytemuck::{Zeroable, bytes_of, pod_read_unaligned};
 use drv_models::{
     constants::nulls::NULL_ORDER,
     state::{
@@ -11,27 +12,30 @@ use drv_models::{
 };
 use solana_sdk::account::Account;
 
+use crate::fill::{FillLeg, FillSource};
+
 #[derive(Clone, Default, Debug, PartialEq)]
 pub struct OrderBook {
     pub lines: Lines,
     pub bid_begin_line: u32,
     pub ask_begin_line: u32,
+    pub bid_lines_count: usize,
+    pub ask_lines_count: usize,
     pub total_lines_count: usize,
 }
 
 impl OrderBook {
     pub fn new(instr_header: &InstrAccountHeader, lines_acc: &Account) -> Self {
-        let lines = if lines_acc.data.len() <= SPOT_TRADE_ACCOUNT_HEADER_SIZE {
-            vec![]
-        } else {
-            Lines::new_lines(cast_slice(
-                &lines_acc.data.as_slice()[SPOT_TRADE_ACCOUNT_HEADER_SIZE..],
-            ))
-        };
+        let lines = lines_acc
+            .data
+            .get(SPOT_TRADE_ACCOUNT_HEADER_SIZE..)
+            .map_or_else(Lines::default, Lines::new);
 
         OrderBook {
             bid_begin_line: instr_header.bid_lines_begin,
             ask_begin_line: instr_header.ask_lines_begin,
+            bid_lines_count: instr_header.bid_lines_count as usize,
+            ask_lines_count: instr_header.ask_lines_count as usize,
             total_lines_count: instr_header
                 .ask_lines_count
                 .max(instr_header.bid_lines_count) as usize,
@@ -49,6 +53,68 @@ impl OrderBook {
             .iter_from(self.ask_begin_line, self.total_lines_count)
     }
 
+    /// Walks `side` reporting broken links instead of stopping silently, nothing is yielded
+    /// for a side without lines
+    pub fn checked_iter<'a>(&'a self, side: OrderSide) -> CheckedLinesIter<'a> {
+        let idx = self.begin_index(side);
+        let count = match side {
+            OrderSide::Bid => self.bid_lines_count,
+            OrderSide::Ask => self.ask_lines_count,
+        };
+        let empty = count == 0
+            || self
+                .lines
+                .get(idx)
+                .is_some_and(|line| line.sref == NULL_ORDER);
+
+        CheckedLinesIter {
+            lines: &self.lines,
+            current: (!empty).then_some(idx as u32),
+            steps: 0,
+        }
+    }
+
+    /// Checks the links of both sides, their price ordering and the line counts
+    pub fn validate(&self) -> Result<()> {
+        for side in [OrderSide::Bid, OrderSide::Ask] {
+            let name = side_name(side);
+            let expected_count = match side {
+                OrderSide::Bid => self.bid_lines_count,
+                OrderSide::Ask => self.ask_lines_count,
+            };
+
+            let mut count = 0;
+            let mut prev_px = None;
+
+            for line in self.checked_iter(side) {
+                let (idx, line) = line?;
+
+                let ordered = prev_px.is_none_or(|prev_px| match side {
+                    OrderSide::Bid => line.price <= prev_px,
+                    OrderSide::Ask => line.price >= prev_px,
+                });
+
+                if !ordered {
+                    bail!("{} line {} breaks the price ordering", name, idx)
+                }
+
+                prev_px = Some(line.price);
+                count += 1;
+            }
+
+            if count != expected_count {
+                bail!(
+                    "{} lines count mismatch: {} linked, {} expected",
+                    name,
+                    count,
+                    expected_count
+                )
+            }
+        }
+
+        Ok(())
+    }
+
     fn begin_index(&self, side: OrderSide) -> usize {
         match side {
             OrderSide::Bid => self.bid_begin_line as usize,
@@ -56,17 +122,192 @@ impl OrderBook {
         }
     }
 
-    pub fn begin(&self, side: OrderSide) -> Option<&PxOrders> {
-        let idx = self.begin_index(side);
+    pub fn begin(&self, side: OrderSide) -> Option<PxOrders> {
+        self.lines
+            .get(self.begin_index(side))
+            .filter(|line| line.sref != NULL_ORDER)
+    }
+
+    /// Takes the qty matched by the line `legs` off `side`, drained lines are unlinked.
+    /// Legs that took no qty, a sum too small for a unit of the line, are skipped.
+    /// Returns how many lines were removed.
+    pub fn apply_fills(&mut self, side: OrderSide, legs: &[FillLeg]) -> Result<usize> {
+        let mut removed = 0;
+
+        for leg in legs {
+            let FillSource::Line(idx) = leg.source else {
+                continue;
+            };
+
+            if leg.qty == 0 {
+                continue;
+            }
+
+            if self.consume(side, idx, leg.qty)? {
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Takes `qty` off the line `idx` of `side`, returns whether the drained line was unlinked
+    pub fn consume(&mut self, side: OrderSide, idx: u32, qty: i64) -> Result<bool> {
+        let mut line = None;
+        for entry in self.checked_iter(side) {
+            let (line_idx, entry) = entry?;
+
+            if line_idx == idx {
+                line = Some(entry);
+                break;
+            }
+        }
+
+        let line = line.ok_or(anyhow!(
+            "Line {} is not on the {} side",
+            idx,
+            side_name(side)
+        ))?;
+
+        if qty <= 0 || qty > line.qty {
+            bail!("Can't take {} from line {} holding {}", qty, idx, line.qty)
+        }
+
+        if line.qty > qty {
+            self.lines.update(idx as usize, |line| line.qty -= qty);
+            return Ok(false);
+        }
+
+        self.unlink(side, idx)?;
+
+        Ok(true)
+    }
+
+    /// Removes the line `idx` from `side`, relinking its neighbours
+    pub fn unlink(&mut self, side: OrderSide, idx: u32) -> Result<()> {
+        let prev = self.predecessor(side, idx)?;
+        let next = self
+            .lines
+            .update(idx as usize, |line| {
+                let next = line.next;
+                line.qty = 0;
+                line.next = NULL_ORDER;
+                line.prev = NULL_ORDER;
+                line.sref = NULL_ORDER;
+                next
+            })
+            .ok_or(anyhow!("Line {} is out of range", idx))?;
+
+        match prev {
+            Some(prev) => {
+                self.lines.update(prev as usize, |line| line.next = next);
+            }
+            None => self.set_begin(side, next),
+        }
+
+        self.lines
+            .update(next as usize, |line| line.prev = prev.unwrap_or(NULL_ORDER));
+
+        match side {
+            OrderSide::Bid => self.bid_lines_count = self.bid_lines_count.saturating_sub(1),
+            OrderSide::Ask => self.ask_lines_count = self.ask_lines_count.saturating_sub(1),
+        }
+
+        Ok(())
+    }
+
+    /// Rests `qty` at `price` on `side`. Joins the line already at that price, otherwise
+    /// links a new line keeping the price order. Returns the line index.
+    pub fn insert(&mut self, side: OrderSide, price: i64, qty: i64) -> Result<u32> {
+        if price <= 0 || qty <= 0 {
+            bail!("Invalid line {} at {}", qty, price)
+        }
+
+        let mut prev = None;
+        let mut next = NULL_ORDER;
+        let mut same_px = None;
+
+        for line in self.checked_iter(side) {
+            let (idx, line) = line?;
+
+            if line.price == price {
+                same_px = Some(idx);
+                break;
+            }
+
+            let better = match side {
+                OrderSide::Bid => price > line.price,
+                OrderSide::Ask => price < line.price,
+            };
+
+            if better {
+                next = idx;
+                break;
+            }
+
+            prev = Some(idx);
+        }
+
+        if let Some(idx) = same_px {
+            self.lines.update(idx as usize, |line| line.qty += qty);
+            return Ok(idx);
+        }
+
+        let idx = self.lines.len() as u32;
+        if idx >= NULL_ORDER {
+            bail!("Lines are full")
+        }
+
+        self.lines.push(PxOrders {
+            price,
+            qty,
+            next,
+            prev: prev.unwrap_or(NULL_ORDER),
+            sref: 0,
+            ..Zeroable::zeroed()
+        });
+
+        match prev {
+            Some(prev) => {
+                self.lines.update(prev as usize, |line| line.next = idx);
+            }
+            None => self.set_begin(side, idx),
+        }
 
-        let line = self.lines.get(idx);
-        if let Some(line) = line {
-            if line.sref == NULL_ORDER {
-                return None;
+        self.lines.update(next as usize, |line| line.prev = idx);
+
+        let count = match side {
+            OrderSide::Bid => &mut self.bid_lines_count,
+            OrderSide::Ask => &mut self.ask_lines_count,
+        };
+        *count += 1;
+        self.total_lines_count = self.total_lines_count.max(*count);
+
+        Ok(idx)
+    }
+
+    /// Line linked before `idx` on `side`, `None` when `idx` is the head
+    fn predecessor(&self, side: OrderSide, idx: u32) -> Result<Option<u32>> {
+        let mut prev = None;
+
+        for line in self.checked_iter(side) {
+            let (line_idx, _) = line?;
+
+            if line_idx == idx {
+                return Ok(prev);
             }
+
+            prev = Some(line_idx);
         }
 
-        line
+        bail!("Line {} is not on the {} side", idx, side_name(side))
+    }
+
+    fn set_begin(&mut self, side: OrderSide, idx: u32) {
+        match side {
+            OrderSide::Bid => self.bid_begin_line = idx,
+            OrderSide::Ask => self.ask_begin_line = idx,
+        }
     }
 
     pub fn cross(&self, price: i64, side: OrderSide) -> bool {
@@ -78,33 +319,90 @@ impl OrderBook {
     }
 }
 
-pub type Lines = Vec<PxOrders>;
-
-pub trait LinesSugar {
-    fn new_lines(slice: &[PxOrders]) -> Self;
-    fn iter_from<'a>(&'a self, start_idx: u32, lines_count: usize) -> LinesIter<'a>;
+fn side_name(side: OrderSide) -> &'static str {
+    match side {
+        OrderSide::Bid => "Bid",
+        OrderSide::Ask => "Ask",
+    }
 }
 
-pub trait LinesMutSugar {
-    fn iter_mut_from<'a>(&'a mut self, start_idx: u32) -> LinesIterMut<'a>;
+const LINE_SIZE: usize = size_of::<PxOrders>();
+
+/// Payload of the lines account, shared between clones of the market and copied on the
+/// first local write. Lines are read out of the bytes on access, an update of the account
+/// only copies its bytes once.
+#[derive(Clone, Default, PartialEq)]
+pub struct Lines {
+    data: Arc<[u8]>,
 }
 
-impl LinesSugar for Lines {
-    fn new_lines(slice: &[PxOrders]) -> Self {
-        slice.to_vec()
+impl Lines {
+    /// Keeps the lines account payload, a trailing partial line is ignored
+    pub fn new(data: &[u8]) -> Self {
+        Lines {
+            data: data[..data.len() - data.len() % LINE_SIZE].into(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() / LINE_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<PxOrders> {
+        if idx >= self.len() {
+            return None;
+        }
+
+        Some(pod_read_unaligned(
+            &self.data[idx * LINE_SIZE..(idx + 1) * LINE_SIZE],
+        ))
+    }
+
+    /// Whether both still share their bytes, neither was written since they were cloned
+    pub fn ptr_eq(&self, other: &Lines) -> bool {
+        Arc::ptr_eq(&self.data, &other.data)
     }
 
-    fn iter_from<'a>(&'a self, start_idx: u32, lines_count: usize) -> LinesIter<'a> {
+    pub fn iter_from<'a>(&'a self, start_idx: u32, lines_count: usize) -> LinesIter<'a> {
         LinesIter {
-            slice: self.as_slice(),
+            lines: self,
             current: Some(start_idx),
             remaining: lines_count,
         }
     }
+
+    /// Applies `f` to the line `idx`, `None` when it is out of range
+    pub fn update<R>(&mut self, idx: usize, f: impl FnOnce(&mut PxOrders) -> R) -> Option<R> {
+        let mut line = self.get(idx)?;
+        let res = f(&mut line);
+
+        Arc::make_mut(&mut self.data)[idx * LINE_SIZE..(idx + 1) * LINE_SIZE]
+            .copy_from_slice(bytes_of(&line));
+
+        Some(res)
+    }
+
+    fn push(&mut self, line: PxOrders) {
+        let mut data = self.data.to_vec();
+        data.extend_from_slice(bytes_of(&line));
+        self.data = data.into();
+    }
+}
+
+impl Debug for Lines {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries((0..self.len()).filter_map(|idx| self.get(idx)))
+            .finish()
+    }
 }
 
 pub struct LinesIter<'a> {
-    slice: &'a [PxOrders],
+    lines: &'a Lines,
     current: Option<u32>,
     remaining: usize,
 }
@@ -126,7 +424,9 @@ impl<'a> Iterator for LinesIter<'a> {
             return None;
         }
 
-        let entry = self.slice[idx as usize];
+        let Some(entry) = self.lines.get(idx as usize) else {
+            return None;
+        };
         self.remaining = self.remaining.saturating_sub(1);
 
         let next_idx = entry.next;
@@ -140,41 +440,40 @@ impl<'a> Iterator for LinesIter<'a> {
     }
 }
 
-pub struct LinesIterMut<'a> {
-    ptr: *mut PxOrders,
+/// Walks a side like [`LinesIter`], but yields an error for a broken link and stops there
+pub struct CheckedLinesIter<'a> {
+    lines: &'a Lines,
     current: Option<u32>,
-    remaining: usize,
-    market: PhantomData<&'a ()>,
+    /// Lines yielded so far, a walk without a cycle can't yield more than the account holds
+    steps: usize,
 }
 
-impl<'a> Iterator for LinesIterMut<'a> {
-    type Item = (u32, &'a mut PxOrders);
+impl<'a> Iterator for CheckedLinesIter<'a> {
+    type Item = Result<(u32, PxOrders)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.remaining == 0 {
+        let idx = self.current.take()?;
+
+        if idx == NULL_ORDER {
             return None;
         }
 
-        let idx = match self.current.take() {
-            Some(i) => i,
-            None => return None,
+        let Some(entry) = self.lines.get(idx as usize) else {
+            return Some(Err(anyhow!(
+                "Line {} is out of range, {} lines",
+                idx,
+                self.lines.len()
+            )));
         };
 
-        if idx == NULL_ORDER {
-            return None;
+        if self.steps == self.lines.len() {
+            return Some(Err(anyhow!("Lines cycle at line {}", idx)));
         }
 
-        let entry: &'a mut PxOrders = unsafe { &mut *self.ptr.add(idx as usize) };
-
-        self.remaining = self.remaining.saturating_sub(1);
+        self.steps += 1;
 
-        let next_idx = entry.next;
-        if next_idx == NULL_ORDER || next_idx == idx {
-            self.current = None;
-        } else {
-            self.current = Some(next_idx);
-        }
+        self.current = Some(entry.next);
 
-        Some((idx, entry))
+        Some(Ok((idx, entry)))
     }
 }
@@ -0,0 +1,104 @@
+//! Address lookup table of an instrument. Swap accounts that don't depend on the user are
+//! stored in the table, a swap then refers to each of them with a one byte index instead of
+//! the whole address.
+
+use anyhow::{Result, anyhow};
+use solana_sdk::{
+    account::Account,
+    address_lookup_table::{
+        self,
+        instruction::{create_lookup_table, extend_lookup_table},
+        state::AddressLookupTable,
+    },
+    instruction::Instruction,
+    pubkey::Pubkey,
+};
+
+/// Addresses added by one `ExtendLookupTable` instruction, keeps the transaction under the
+/// packet size
+pub const MAX_EXTEND_ADDRESSES: usize = 30;
+
+/// Swap accounts that can't go into the table: the transfer authority and the user's token
+/// accounts
+pub const USER_SWAP_ACCOUNTS: usize = 3;
+
+/// Addresses stored in a lookup table account
+#[derive(Clone, Debug, PartialEq)]
+pub struct LookupTable {
+    pub address: Pubkey,
+    pub addresses: Vec<Pubkey>,
+}
+
+impl LookupTable {
+    pub fn new(address: &Pubkey, account: &Account) -> Result<Self> {
+        if account.owner != address_lookup_table::program::id() {
+            return Err(anyhow!(
+                "Lookup table {} is owned by {}",
+                address,
+                account.owner
+            ));
+        }
+
+        let table = AddressLookupTable::deserialize(&account.data)
+            .map_err(|err| anyhow!("Invalid lookup table {}: {}", address, err))?;
+
+        Ok(LookupTable {
+            address: *address,
+            addresses: table.addresses.to_vec(),
+        })
+    }
+
+    pub fn contains(&self, address: &Pubkey) -> bool {
+        self.addresses.contains(address)
+    }
+
+    /// `accounts` the table doesn't hold yet, in their original order
+    pub fn missing(&self, accounts: &[Pubkey]) -> Vec<Pubkey> {
+        accounts
+            .iter()
+            .filter(|account| !self.contains(account))
+            .copied()
+            .collect()
+    }
+}
+
+/// Creates the table of an instrument and fills it with `accounts`, the table address is
+/// derived from `authority` and `recent_slot`
+///
+/// Each instruction goes into its own transaction, the table is only usable a slot after
+/// the last extension
+pub fn create_lookup_table_instructions(
+    authority: &Pubkey,
+    payer: &Pubkey,
+    recent_slot: u64,
+    accounts: &[Pubkey],
+) -> (Pubkey, Vec<Instruction>) {
+    let (create_ix, address) = create_lookup_table(*authority, *payer, recent_slot);
+
+    let mut instructions = vec![create_ix];
+    instructions.extend(extend_instructions(&address, authority, payer, accounts));
+
+    (address, instructions)
+}
+
+/// Adds the `accounts` missing from `table`, nothing when the table is complete
+pub fn extend_lookup_table_instructions(
+    table: &LookupTable,
+    authority: &Pubkey,
+    payer: &Pubkey,
+    accounts: &[Pubkey],
+) -> Vec<Instruction> {
+    extend_instructions(&table.address, authority, payer, &table.missing(accounts))
+}
+
+fn extend_instructions(
+    address: &Pubkey,
+    authority: &Pubkey,
+    payer: &Pubkey,
+    accounts: &[Pubkey],
+) -> Vec<Instruction> {
+    accounts
+        .chunks(MAX_EXTEND_ADDRESSES)
+        .map(|chunk| extend_lookup_table(*address, *authority, Some(*payer), chunk.to_vec()))
+        .collect()
+}
@@ -0,0 +1,296 @@
+use anyhow::{Result, anyhow};
+use drv_models::state::types::{OrderSide, PxOrders};
+
+use crate::{
+    amm::DeriverseAmm,
+    fill::{FillLeg, FillTrace},
+    lines_linked_list::CheckedLinesIter,
+    math::mul_div,
+};
+
+/// Amount bounding a match, in the units the taker controls
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Budget {
+    /// Asset tokens to buy or sell
+    Qty(i64),
+    /// Currency to spend or receive, fees excluded
+    Sum(i64),
+}
+
+impl Budget {
+    fn amount(&self) -> i64 {
+        match *self {
+            Budget::Qty(amount) | Budget::Sum(amount) => amount,
+        }
+    }
+}
+
+/// Totals of a single [`MatchingEngine::fill`] call
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Fill {
+    pub qty: i64,
+    pub sum: i64,
+    pub fees: i64,
+    /// Part of the budget left unmatched
+    pub remaining: i64,
+}
+
+impl Fill {
+    fn add(&mut self, qty: i64, sum: i64, fee: i64) -> Result<()> {
+        self.qty = self
+            .qty
+            .checked_add(qty)
+            .ok_or(anyhow!("Arithmetic Overflow"))?;
+        self.sum = self
+            .sum
+            .checked_add(sum)
+            .ok_or(anyhow!("Arithmetic Overflow"))?;
+        self.fees = self
+            .fees
+            .checked_add(fee)
+            .ok_or(anyhow!("Arithmetic Overflow"))?;
+
+        Ok(())
+    }
+
+    /// Accumulates a following fill, `remaining` is taken from `other`
+    pub fn merge(&mut self, other: &Fill) -> Result<()> {
+        self.add(other.qty, other.sum, other.fees)?;
+        self.remaining = other.remaining;
+
+        Ok(())
+    }
+
+    /// Average execution price, `None` while nothing is filled
+    pub fn avg_px(&self, dec_factor: i64) -> Option<i64> {
+        if self.qty <= 0 {
+            return None;
+        }
+
+        mul_div(self.sum as i128, dec_factor as i128, self.qty as i128)
+            .and_then(|px| i64::try_from(px).ok())
+    }
+}
+
+/// Matches a taker against one side of the book and the AMM curve in price order.
+///
+/// `side` is the resting side being consumed: `Ask` when the taker buys the asset, `Bid`
+/// when the taker sells it. The engine keeps its position between [`Self::fill`] calls,
+/// so consecutive fills continue where the previous one stopped. A broken link of the side
+/// fails the match instead of ending the liquidity there.
+pub struct MatchingEngine<'a> {
+    side: OrderSide,
+    limit_px: i64,
+    amm: DeriverseAmm,
+    lines: CheckedLinesIter<'a>,
+    /// Line being consumed, `qty` holds what is still resting on it
+    current: Option<(u32, PxOrders)>,
+    trace: FillTrace,
+}
+
+impl<'a> MatchingEngine<'a> {
+    pub fn new(
+        amm: DeriverseAmm,
+        lines: CheckedLinesIter<'a>,
+        side: OrderSide,
+        limit_px: i64,
+        fee_rate: f64,
+    ) -> Self {
+        MatchingEngine {
+            side,
+            limit_px,
+            trace: FillTrace::new(fee_rate, amm.dec_factor),
+            amm,
+            lines,
+            current: None,
+        }
+    }
+
+    pub fn side(&self) -> OrderSide {
+        self.side
+    }
+
+    pub fn limit_px(&self) -> i64 {
+        self.limit_px
+    }
+
+    pub fn amm(&self) -> &DeriverseAmm {
+        &self.amm
+    }
+
+    pub fn legs(&self) -> &[FillLeg] {
+        &self.trace.legs
+    }
+
+    pub fn into_parts(self) -> (DeriverseAmm, Vec<FillLeg>) {
+        (self.amm, self.trace.legs)
+    }
+
+    /// Whether liquidity is left past the limit price, i.e. an unfilled remainder was cut by
+    /// the limit rather than by exhausted liquidity
+    pub fn limit_reached(&mut self) -> Result<bool> {
+        Ok((self.amm.a_tokens > 0 && self.amm.b_tokens > 0) || self.peek_line()?.is_some())
+    }
+
+    /// Price of the next unit of liquidity on the consumed side, `None` when both the book
+    /// and the AMM are drained
+    pub fn marginal_px(&mut self) -> Result<Option<i64>> {
+        let amm_px = self.amm.spot_px();
+        let line_px = self.peek_line()?.map(|(_, line)| line.price);
+
+        Ok(match (amm_px, line_px) {
+            (Some(amm_px), Some(line_px)) => Some(match self.side {
+                OrderSide::Ask => amm_px.min(line_px),
+                OrderSide::Bid => amm_px.max(line_px),
+            }),
+            (px, None) | (None, px) => px,
+        })
+    }
+
+    /// Consumes liquidity until `budget` is matched, the limit price is reached or the
+    /// book and the AMM run dry
+    pub fn fill(&mut self, budget: Budget) -> Result<Fill> {
+        let mut fill = Fill {
+            remaining: budget.amount(),
+            ..Fill::default()
+        };
+
+        while fill.remaining > 0 {
+            let line = self.peek_line()?.filter(|(_, line)| {
+                !DeriverseAmm::line_is_unreachable(self.limit_px, line.price, self.side)
+            });
+
+            // AMM leg up to the next line or to the limit price
+            let target_px = match line {
+                Some((_, line)) => match self.side {
+                    OrderSide::Ask => line.price.min(self.limit_px),
+                    OrderSide::Bid => line.price.max(self.limit_px),
+                },
+                None => self.limit_px,
+            };
+
+            let (traded_qty, traded_mints) = self.amm_leg(target_px, budget, fill.remaining)?;
+
+            if traded_qty > 0 && traded_mints > 0 {
+                self.amm.apply_trade(traded_qty, traded_mints, self.side)?;
+                let fee = self.fee(budget, traded_mints);
+                self.trace.amm(traded_qty, traded_mints, fee);
+
+                fill.add(traded_qty, traded_mints, fee)?;
+                fill.remaining -= match budget {
+                    Budget::Qty(_) => traded_qty,
+                    Budget::Sum(_) => traded_mints,
+                };
+            }
+
+            let Some((idx, line)) = line else {
+                break;
+            };
+
+            if fill.remaining <= 0 {
+                break;
+            }
+
+            let (fill_qty, fill_sum) = self.line_leg(&line, budget, fill.remaining)?;
+
+            let fee = self.fee(budget, fill_sum);
+            self.trace.line(idx, line.price, fill_qty, fill_sum, fee);
+            fill.add(fill_qty, fill_sum, fee)?;
+            fill.remaining -= match budget {
+                Budget::Qty(_) => fill_qty,
+                Budget::Sum(_) => fill_sum,
+            };
+
+            let left = line.qty - fill_qty;
+            self.current = if left > 0 {
+                Some((idx, PxOrders { qty: left, ..line }))
+            } else {
+                None
+            };
+        }
+
+        Ok(fill)
+    }
+
+    /// Fee charged on `sum`. Buying a set qty rounds it up, so that the input quoted for the
+    /// qty covers the fee again when sent as an ExactIn amount
+    fn fee(&self, budget: Budget, sum: i64) -> i64 {
+        match (budget, self.side) {
+            (Budget::Qty(_), OrderSide::Ask) => self.trace.fee_ceil(sum),
+            _ => self.trace.fee(sum),
+        }
+    }
+
+    fn peek_line(&mut self) -> Result<Option<(u32, PxOrders)>> {
+        if self.current.is_none() {
+            self.current = self.lines.next().transpose()?;
+        }
+
+        Ok(self.current)
+    }
+
+    /// Qty and sum traded with the AMM when moving its price towards `target_px`,
+    /// capped by the remaining budget
+    fn amm_leg(&self, target_px: i64, budget: Budget, remaining: i64) -> Result<(i64, i64)> {
+        let amm = &self.amm;
+
+        Ok(match (budget, self.side) {
+            // The taker never pays less than the curve asks for
+            (Budget::Qty(_), OrderSide::Ask) => {
+                let qty = amm.get_amm_qty(target_px, OrderSide::Ask)?.min(remaining);
+                (qty, amm.get_amm_sum_ceil(qty)?)
+            }
+            (Budget::Qty(_), OrderSide::Bid) => {
+                let qty = amm.get_amm_qty(target_px, OrderSide::Bid)?.min(remaining);
+                (qty, amm.get_amm_sum(qty, OrderSide::Bid)?)
+            }
+            (Budget::Sum(_), OrderSide::Ask) => {
+                let sum = amm.get_reversed_amm_sum(target_px)?.min(remaining);
+                (amm.get_reversed_amm_qty(sum)?, sum)
+            }
+            (Budget::Sum(_), OrderSide::Bid) => {
+                let qty = amm.get_amm_qty(target_px, OrderSide::Bid)?;
+                let sum = amm.get_amm_sum(qty, OrderSide::Bid)?;
+
+                if sum > remaining {
+                    (
+                        amm.get_amm_qty_for_sum(remaining, OrderSide::Bid)?,
+                        remaining,
+                    )
+                } else {
+                    (qty, sum)
+                }
+            }
+        })
+    }
+
+    /// Qty and sum taken from `line`, capped by the remaining budget
+    fn line_leg(&self, line: &PxOrders, budget: Budget, remaining: i64) -> Result<(i64, i64)> {
+        let amm = &self.amm;
+
+        Ok(match budget {
+            Budget::Qty(_) => {
+                let qty = line.qty.min(remaining);
+                let sum = match self.side {
+                    OrderSide::Ask => amm.trade_sum_ceil(qty, line.price)?,
+                    OrderSide::Bid => amm.trade_sum(qty, line.price)?,
+                };
+                (qty, sum)
+            }
+            Budget::Sum(_) => {
+                let line_sum = amm.trade_sum(line.qty, line.price)?;
+
+                if line_sum <= remaining {
+                    (line.qty, line_sum)
+                } else {
+                    // The taker never receives more than it pays for
+                    let qty = match self.side {
+                        OrderSide::Ask => amm.trade_qty(remaining, line.price)?,
+                        OrderSide::Bid => amm.trade_qty_ceil(remaining, line.price)?,
+                    };
+                    (qty, remaining)
+                }
+            }
+        })
+    }
+}
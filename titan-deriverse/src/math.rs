@@ -0,0 +1,150 @@
+//! Fixed-point helpers reproducing the integer arithmetic of the on-chain AMM.
+//!
+//! Every result is rounded down, like the program does. The `f64-math` feature swaps the
+//! exact 256-bit intermediate math for the faster floating point approximation.
+
+#[cfg(not(feature = "f64-math"))]
+const LOW_MASK: u128 = u64::MAX as u128;
+
+/// `floor(a * b / c)` for non-negative operands, `None` on overflow or zero divisor
+#[cfg(not(feature = "f64-math"))]
+pub fn mul_div(a: i128, b: i128, c: i128) -> Option<i128> {
+    if a < 0 || b < 0 || c <= 0 {
+        return None;
+    }
+
+    let (hi, lo) = wide_mul(a as u128, b as u128);
+    let (quot, _) = wide_div(hi, lo, c as u128)?;
+
+    i128::try_from(quot).ok()
+}
+
+#[cfg(feature = "f64-math")]
+pub fn mul_div(a: i128, b: i128, c: i128) -> Option<i128> {
+    if a < 0 || b < 0 || c <= 0 {
+        return None;
+    }
+
+    let res = a as f64 * b as f64 / c as f64;
+
+    if res.is_finite() && res < i128::MAX as f64 {
+        Some(res as i128)
+    } else {
+        None
+    }
+}
+
+/// `ceil(a * b / c)` for non-negative operands, `None` on overflow or zero divisor
+#[cfg(not(feature = "f64-math"))]
+pub fn mul_div_ceil(a: i128, b: i128, c: i128) -> Option<i128> {
+    if a < 0 || b < 0 || c <= 0 {
+        return None;
+    }
+
+    let (hi, lo) = wide_mul(a as u128, b as u128);
+    let (quot, rem) = wide_div(hi, lo, c as u128)?;
+    let quot = if rem != 0 { quot.checked_add(1)? } else { quot };
+
+    i128::try_from(quot).ok()
+}
+
+#[cfg(feature = "f64-math")]
+pub fn mul_div_ceil(a: i128, b: i128, c: i128) -> Option<i128> {
+    if a < 0 || b < 0 || c <= 0 {
+        return None;
+    }
+
+    let res = (a as f64 * b as f64 / c as f64).ceil();
+
+    if res.is_finite() && res < i128::MAX as f64 {
+        Some(res as i128)
+    } else {
+        None
+    }
+}
+
+/// `floor(sqrt(a * b / c))`, the intermediate ratio is rounded down first as on chain
+#[cfg(not(feature = "f64-math"))]
+pub fn sqrt_mul_div(a: i128, b: i128, c: i128) -> Option<i128> {
+    mul_div(a, b, c).map(|ratio| isqrt(ratio as u128) as i128)
+}
+
+#[cfg(feature = "f64-math")]
+pub fn sqrt_mul_div(a: i128, b: i128, c: i128) -> Option<i128> {
+    if a < 0 || b < 0 || c <= 0 {
+        return None;
+    }
+
+    let res = (a as f64 * b as f64 / c as f64).sqrt();
+
+    if res.is_finite() && res < i128::MAX as f64 {
+        Some(res as i128)
+    } else {
+        None
+    }
+}
+
+/// `floor(sqrt(n))` computed with Newton iterations starting above the root
+pub fn isqrt(n: u128) -> u128 {
+    if n < 2 {
+        return n;
+    }
+
+    let bits = 128 - n.leading_zeros();
+    let mut x = 1u128 << bits.div_ceil(2);
+
+    loop {
+        let y = (x + n / x) >> 1;
+        if y >= x {
+            return x;
+        }
+        x = y;
+    }
+}
+
+/// Full 256-bit product as `(hi, lo)` words
+#[cfg(not(feature = "f64-math"))]
+fn wide_mul(a: u128, b: u128) -> (u128, u128) {
+    let (a_hi, a_lo) = (a >> 64, a & LOW_MASK);
+    let (b_hi, b_lo) = (b >> 64, b & LOW_MASK);
+
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let hi_hi = a_hi * b_hi;
+
+    let cross = (lo_lo >> 64) + (hi_lo & LOW_MASK) + (lo_hi & LOW_MASK);
+
+    let lo = (cross << 64) | (lo_lo & LOW_MASK);
+    let hi = hi_hi + (hi_lo >> 64) + (lo_hi >> 64) + (cross >> 64);
+
+    (hi, lo)
+}
+
+/// Divides the 256-bit `(hi, lo)` by `d`, `None` if the quotient does not fit in 128 bits
+#[cfg(not(feature = "f64-math"))]
+fn wide_div(hi: u128, lo: u128, d: u128) -> Option<(u128, u128)> {
+    if d == 0 || hi >= d {
+        return None;
+    }
+
+    if hi == 0 {
+        return Some((lo / d, lo % d));
+    }
+
+    let mut rem = hi;
+    let mut quot = 0u128;
+
+    for i in (0..128).rev() {
+        let carry = rem >> 127;
+        rem = (rem << 1) | ((lo >> i) & 1);
+        quot <<= 1;
+
+        if carry == 1 || rem >= d {
+            rem = rem.wrapping_sub(d);
+            quot |= 1;
+        }
+    }
+
+    Some((quot, rem))
+}
@@ -0,0 +1,138 @@
+//! Order-level view of the book decoded from the `SPOT_BID_ORDERS` / `SPOT_ASK_ORDERS`
+//! accounts. Orders are reached from their lines: `PxOrders::sref` is read as the first
+//! order of the line queue and `Order::next` links the rest in time priority. The qty of a
+//! queue has to add up to the qty of its line, a line whose `sref` doesn't lead to its own
+//! orders fails the decode instead of borrowing the orders of another line.
+
+use std::mem::size_of;
+
+use anyhow::{Result, anyhow, bail};
+use bytemuck::pod_read_unaligned;
+use drv_models::{
+    constants::nulls::NULL_ORDER,
+    state::{
+        spots::spot_account_header::SPOT_TRADE_ACCOUNT_HEADER_SIZE,
+        types::{Order, OrderSide},
+    },
+};
+use solana_sdk::account::Account;
+
+use crate::lines_linked_list::OrderBook;
+
+/// Resting order, sides are kept in price-time priority
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpotOrder {
+    /// Index in the orders account
+    pub idx: u32,
+    /// Index of the line the order rests on
+    pub line: u32,
+    pub client_id: u32,
+    pub price: i64,
+    pub qty: i64,
+    pub time: u32,
+    /// Qty matched before this order on its side
+    pub qty_ahead: i64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OrdersBook {
+    pub bids: Vec<SpotOrder>,
+    pub asks: Vec<SpotOrder>,
+}
+
+impl OrdersBook {
+    pub fn new(
+        order_book: &OrderBook,
+        bid_orders_acc: &Account,
+        ask_orders_acc: &Account,
+    ) -> Result<Self> {
+        Ok(OrdersBook {
+            bids: decode_side(order_book, OrderSide::Bid, &bid_orders_acc.data)?,
+            asks: decode_side(order_book, OrderSide::Ask, &ask_orders_acc.data)?,
+        })
+    }
+
+    pub fn side(&self, side: OrderSide) -> &[SpotOrder] {
+        match side {
+            OrderSide::Bid => &self.bids,
+            OrderSide::Ask => &self.asks,
+        }
+    }
+
+    pub fn client_orders(&self, client_id: u32) -> impl Iterator<Item = (OrderSide, &SpotOrder)> {
+        let bids = self.bids.iter().map(|order| (OrderSide::Bid, order));
+        let asks = self.asks.iter().map(|order| (OrderSide::Ask, order));
+
+        bids.chain(asks)
+            .filter(move |(_, order)| order.client_id == client_id)
+    }
+
+    /// `order_book` without the qty of the client's orders, so quotes never self-trade
+    pub fn exclude_client(&self, order_book: &OrderBook, client_id: u32) -> Result<OrderBook> {
+        let mut order_book = order_book.clone();
+
+        for (side, order) in self.client_orders(client_id) {
+            order_book.consume(side, order.line, order.qty)?;
+        }
+
+        Ok(order_book)
+    }
+}
+
+fn decode_side(order_book: &OrderBook, side: OrderSide, data: &[u8]) -> Result<Vec<SpotOrder>> {
+    let orders = data
+        .get(SPOT_TRADE_ACCOUNT_HEADER_SIZE..)
+        .unwrap_or_default()
+        .chunks_exact(size_of::<Order>())
+        .map(pod_read_unaligned::<Order>)
+        .collect::<Vec<_>>();
+
+    let mut visited = vec![false; orders.len()];
+    let mut qty_ahead = 0_i64;
+    let mut spot_orders = vec![];
+
+    for line in order_book.checked_iter(side) {
+        let (line_idx, line) = line?;
+        let mut idx = line.sref;
+        let mut queue_qty = 0_i64;
+
+        while idx != NULL_ORDER {
+            let order = orders.get(idx as usize).ok_or(anyhow!(
+                "Order {} is out of range, {} orders",
+                idx,
+                orders.len()
+            ))?;
+
+            if std::mem::replace(&mut visited[idx as usize], true) {
+                bail!("Orders cycle at order {}", idx)
+            }
+
+            spot_orders.push(SpotOrder {
+                idx,
+                line: line_idx,
+                client_id: order.client_id,
+                price: line.price,
+                qty: order.qty,
+                time: order.time,
+                qty_ahead,
+            });
+
+            qty_ahead = qty_ahead
+                .checked_add(order.qty)
+                .ok_or(anyhow!("Arithmetic Overflow"))?;
+            queue_qty += order.qty;
+            idx = order.next;
+        }
+
+        if queue_qty != line.qty {
+            bail!(
+                "Line {} holds {} qty, its orders {}",
+                line_idx,
+                line.qty,
+                queue_qty
+            )
+        }
+    }
+
+    Ok(spot_orders)
+}
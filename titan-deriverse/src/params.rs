@@ -0,0 +1,145 @@
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Deserializer, de};
+use serde_json::Value;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::clock::{ClockStamp, StaleState};
+
+/// Default band around the market price, matches the former hard-coded `px >> 3`
+pub const DEFAULT_SLIPPAGE_BPS: u32 = 1_250;
+
+const BPS_DENOMINATOR: i128 = 10_000;
+
+/// Worst price a quote is allowed to execute at
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PriceLimit {
+    /// Absolute limit price, same scale as the instrument prices
+    Price(i64),
+    /// Maximum move away from the market price in basis points
+    SlippageBps(u32),
+}
+
+impl Default for PriceLimit {
+    fn default() -> Self {
+        PriceLimit::SlippageBps(DEFAULT_SLIPPAGE_BPS)
+    }
+}
+
+impl PriceLimit {
+    pub fn limit_px(&self, market_px: i64, buy: bool) -> i64 {
+        match *self {
+            PriceLimit::Price(price) => price,
+            PriceLimit::SlippageBps(bps) => {
+                let max_diff = (market_px as i128 * bps as i128 / BPS_DENOMINATOR) as i64;
+
+                if buy {
+                    market_px.saturating_add(max_diff)
+                } else {
+                    market_px.saturating_sub(max_diff).max(0)
+                }
+            }
+        }
+    }
+}
+
+/// What [`Amm::quote`](jupiter_amm_interface::Amm::quote) does when the requested amount
+/// can't be filled completely. A `Quote` carries no fill status, callers that have to tell
+/// partial quotes apart opt into `Reject`, `Deriverse::quote_detailed` reports them in
+/// either mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PartialFillMode {
+    /// Return the partial quote as if it was filled
+    #[default]
+    Allow,
+    /// Fail the quote with a `PartialFillError`
+    Reject,
+}
+
+/// Limits on the age of the market state, unset limits aren't checked
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MaxAge {
+    /// Slots since the last update
+    pub slots: Option<u64>,
+    /// Seconds since the last update
+    pub seconds: Option<i64>,
+    /// Seconds since the last trade on the instrument, catches frozen markets
+    pub idle_seconds: Option<i64>,
+}
+
+impl MaxAge {
+    /// `last_trade_time` of 0 means the instrument never traded and is not checked
+    pub fn check(
+        &self,
+        now: ClockStamp,
+        last_update: Option<ClockStamp>,
+        last_trade_time: i64,
+    ) -> Option<StaleState> {
+        if self.slots.is_some() || self.seconds.is_some() {
+            let Some(last_update) = last_update else {
+                return Some(StaleState::NeverUpdated);
+            };
+
+            let slots = now.slot.saturating_sub(last_update.slot);
+            let seconds = now
+                .unix_timestamp
+                .saturating_sub(last_update.unix_timestamp)
+                .max(0);
+
+            if self.slots.is_some_and(|max| slots > max)
+                || self.seconds.is_some_and(|max| seconds > max)
+            {
+                return Some(StaleState::Outdated { slots, seconds });
+            }
+        }
+
+        let idle_seconds = now.unix_timestamp.saturating_sub(last_trade_time);
+
+        self.idle_seconds
+            .filter(|_| last_trade_time != 0)
+            .filter(|max| idle_seconds > *max)
+            .map(|_| StaleState::Frozen { idle_seconds })
+    }
+}
+
+/// Market configuration decoded from `KeyedAccount::params`
+///
+/// ```json
+/// {
+///     "priceLimit": { "slippageBps": 50 },
+///     "partialFill": "reject",
+///     "maxAge": { "slots": 150, "idleSeconds": 86400 },
+///     "lookupTable": "<address lookup table of the instrument>"
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DeriverseParams {
+    pub price_limit: PriceLimit,
+    pub partial_fill: PartialFillMode,
+    pub max_age: MaxAge,
+    #[serde(deserialize_with = "deserialize_pubkey")]
+    pub lookup_table: Option<Pubkey>,
+}
+
+impl DeriverseParams {
+    pub fn from_value(params: Option<&Value>) -> Result<Self> {
+        match params {
+            None | Some(Value::Null) => Ok(Self::default()),
+            Some(value) => serde_json::from_value(value.clone())
+                .map_err(|err| anyhow!("Invalid Deriverse params: {}", err)),
+        }
+    }
+}
+
+fn deserialize_pubkey<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Pubkey>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|key| Pubkey::from_str(&key).map_err(de::Error::custom))
+        .transpose()
+}
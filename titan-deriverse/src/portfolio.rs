@@ -0,0 +1,226 @@
+//! Deposited balances of a client, decoded from its primary and community accounts.
+//! Balances are kept per token, so they sum up every instrument the client traded on.
+
+use std::mem::size_of;
+
+use anyhow::{Result, anyhow, bail};
+use bytemuck::{Pod, pod_read_unaligned};
+use drv_models::state::{
+    client_community_account_header::{ClientCommunityAccountHeader, ClientCommunityRecord},
+    client_primary_account_header::{AssetRecord, ClientPrimaryAccountHeader},
+    token::TokenState,
+    types::account_type::{CLIENT_COMMUNITY, CLIENT_PRIMARY},
+};
+use jupiter_amm_interface::AccountMap;
+use rust_decimal::Decimal;
+use solana_sdk::{account::Account, pubkey::Pubkey};
+
+use crate::{
+    AccountsHolder, accounts::validate_program_account, client::primary_header, helper::Helper,
+};
+
+/// Accounts holding the state of a wallet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientAccounts {
+    pub primary: Pubkey,
+    pub community: Pubkey,
+}
+
+impl ClientAccounts {
+    pub fn new(wallet: &Pubkey) -> Self {
+        ClientAccounts {
+            primary: wallet.new_client_primary_acc(),
+            community: wallet.new_client_community_acc(),
+        }
+    }
+}
+
+/// Raw amount of a token, in the token's smallest units
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawBalance {
+    pub token_id: u32,
+    pub amount: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientPrimary {
+    pub id: u32,
+    /// Deposited tokens
+    pub balances: Vec<RawBalance>,
+}
+
+impl ClientPrimary {
+    pub fn new(client_primary_acc: &Account) -> Result<Self> {
+        let header = primary_header(client_primary_acc)?;
+
+        let balances = records::<AssetRecord, ClientPrimaryAccountHeader>(
+            &client_primary_acc.data,
+            header.assets_count,
+        )?
+        .map(|record| RawBalance {
+            token_id: record.asset_id,
+            amount: record.value,
+        })
+        .collect();
+
+        Ok(ClientPrimary {
+            id: header.id,
+            balances,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientCommunity {
+    /// Unclaimed dividends per currency token
+    pub dividends: Vec<RawBalance>,
+}
+
+impl ClientCommunity {
+    pub fn new(client_community_acc: &Account) -> Result<Self> {
+        let header = client_community_acc
+            .data
+            .get(..size_of::<ClientCommunityAccountHeader>())
+            .map(pod_read_unaligned::<ClientCommunityAccountHeader>)
+            .ok_or(anyhow!("Client community account is too short"))?;
+
+        let dividends = records::<ClientCommunityRecord, ClientCommunityAccountHeader>(
+            &client_community_acc.data,
+            header.count,
+        )?
+        .map(|record| RawBalance {
+            token_id: record.crncy_token_id,
+            amount: record.dividends_value,
+        })
+        .collect();
+
+        Ok(ClientCommunity { dividends })
+    }
+}
+
+/// Balance of one token scaled by its decimals
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TokenBalance {
+    pub token_id: u32,
+    pub mint: Pubkey,
+    pub deposited: Decimal,
+    pub dividends: Decimal,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Portfolio {
+    pub client_id: u32,
+    /// Tokens the client holds or is owed dividends in, ordered by token id
+    pub balances: Vec<TokenBalance>,
+}
+
+impl Portfolio {
+    /// `token_states` has to cover every token of the client
+    pub fn new(
+        primary: &ClientPrimary,
+        community: Option<&ClientCommunity>,
+        token_states: &[TokenState],
+    ) -> Result<Self> {
+        let dividends = community.map_or(&[][..], |community| &community.dividends);
+
+        let mut balances = Vec::<TokenBalance>::new();
+
+        for (balance, is_dividend) in primary
+            .balances
+            .iter()
+            .map(|balance| (balance, false))
+            .chain(dividends.iter().map(|balance| (balance, true)))
+        {
+            let token_state = token_states
+                .iter()
+                .find(|token_state| token_state.id == balance.token_id)
+                .ok_or(anyhow!("Token state {} is not provided", balance.token_id))?;
+
+            let amount =
+                Decimal::try_new(balance.amount, token_state.mask & 0xFF).map_err(|err| {
+                    anyhow!("Invalid decimals of token {}: {}", balance.token_id, err)
+                })?;
+
+            let idx = match balances.binary_search_by_key(&balance.token_id, |b| b.token_id) {
+                Ok(idx) => idx,
+                Err(idx) => {
+                    balances.insert(
+                        idx,
+                        TokenBalance {
+                            token_id: balance.token_id,
+                            mint: token_state.address,
+                            deposited: Decimal::ZERO,
+                            dividends: Decimal::ZERO,
+                        },
+                    );
+                    idx
+                }
+            };
+
+            let entry = &mut balances[idx];
+            if is_dividend {
+                entry.dividends += amount;
+            } else {
+                entry.deposited += amount;
+            }
+        }
+
+        Ok(Portfolio {
+            client_id: primary.id,
+            balances,
+        })
+    }
+
+    /// Portfolio of `wallet`, the community account is optional as it only holds dividends
+    pub fn load(
+        wallet: &Pubkey,
+        account_map: &AccountMap,
+        token_states: &[TokenState],
+    ) -> Result<Self> {
+        let ClientAccounts { primary, community } = ClientAccounts::new(wallet);
+
+        let primary = account_map
+            .program_account(
+                &primary,
+                CLIENT_PRIMARY,
+                size_of::<ClientPrimaryAccountHeader>(),
+            )
+            .and_then(ClientPrimary::new)?;
+        let community = account_map
+            .get(&community)
+            .map(|account| {
+                validate_program_account(
+                    &community,
+                    account,
+                    CLIENT_COMMUNITY,
+                    size_of::<ClientCommunityAccountHeader>(),
+                )?;
+                ClientCommunity::new(account)
+            })
+            .transpose()?;
+
+        Portfolio::new(&primary, community.as_ref(), token_states)
+    }
+
+    pub fn balance(&self, mint: &Pubkey) -> Option<&TokenBalance> {
+        self.balances.iter().find(|balance| balance.mint == *mint)
+    }
+}
+
+/// `count` records of type `T` following the header `H`
+fn records<T: Pod, H>(data: &[u8], count: u32) -> Result<impl Iterator<Item = T> + '_> {
+    let records = data
+        .get(size_of::<H>()..)
+        .unwrap_or_default()
+        .chunks_exact(size_of::<T>());
+
+    if records.len() < count as usize {
+        bail!(
+            "Account holds {} records, header expects {}",
+            records.len(),
+            count
+        )
+    }
+
+    Ok(records.take(count as usize).map(pod_read_unaligned::<T>))
+}
@@ -0,0 +1,35 @@
+use drv_models::constants::DF;
+use rust_decimal::Decimal;
+
+/// Converts an instrument price to currency per asset
+pub fn human_px(px: i64) -> Decimal {
+    Decimal::from(px) / Decimal::from(DF as i64)
+}
+
+/// Execution profile of a hypothetical taker order, prices in currency per asset
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PriceImpact {
+    /// Asset qty sent or received by the taker, less than requested when the liquidity
+    /// runs out
+    pub qty: i64,
+    /// Marginal price before the trade
+    pub spot_price: Decimal,
+    pub avg_price: Decimal,
+    /// Marginal price after the trade, `None` once the side is drained
+    pub marginal_price: Option<Decimal>,
+    /// Relative distance between the average and the spot price
+    pub impact: Decimal,
+}
+
+/// Cumulative point of a depth ladder
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DepthLevel {
+    /// Requested input amount
+    pub amount: u64,
+    /// Matched input amount, below `amount` only on the last level
+    pub in_amount: u64,
+    pub out_amount: u64,
+    pub avg_price: Decimal,
+    /// Furthest price touched so far, line price or post-trade AMM price
+    pub worst_price: Decimal,
+}
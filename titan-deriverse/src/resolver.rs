@@ -0,0 +1,173 @@
+//! Finds the instrument trading two mints given in any order. Instruments are derived from
+//! the token ids of their asset and currency, the resolver reads the ids from the token states
+//! of the mints and checks which of both orderings exists.
+
+use std::{collections::HashMap, mem::size_of};
+
+use anyhow::{Result, bail};
+use drv_models::state::{
+    token::TokenState,
+    types::account_type::{INSTR, TOKEN},
+};
+use jupiter_amm_interface::AccountMap;
+use solana_sdk::{account::Account, pubkey::Pubkey};
+
+use crate::{
+    accounts::{MarketMismatch, validate_program_account},
+    discovery::instrument_header,
+    helper::Helper,
+};
+
+#[cfg(feature = "rpc")]
+use solana_client::rpc_client::RpcClient;
+
+/// Source of the accounts read by the resolver
+pub trait AccountFetcher {
+    /// Accounts at `keys` in the same order, `None` for the ones that don't exist
+    fn fetch_accounts(&self, keys: &[Pubkey]) -> Result<Vec<Option<Account>>>;
+}
+
+impl AccountFetcher for AccountMap {
+    fn fetch_accounts(&self, keys: &[Pubkey]) -> Result<Vec<Option<Account>>> {
+        Ok(keys.iter().map(|key| self.get(key).cloned()).collect())
+    }
+}
+
+#[cfg(feature = "rpc")]
+impl AccountFetcher for RpcClient {
+    fn fetch_accounts(&self, keys: &[Pubkey]) -> Result<Vec<Option<Account>>> {
+        Ok(self.get_multiple_accounts(keys)?)
+    }
+}
+
+/// Instrument of a pair with the role of each mint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResolvedPair {
+    /// Instrument header, the key of the market
+    pub key: Pubkey,
+    pub asset_mint: Pubkey,
+    pub crncy_mint: Pubkey,
+}
+
+impl ResolvedPair {
+    pub fn is_asset(&self, mint: &Pubkey) -> bool {
+        self.asset_mint == *mint
+    }
+}
+
+/// Resolves pairs of mints to instruments, token ids never change once assigned and are
+/// cached across calls
+#[derive(Clone, Debug, Default)]
+pub struct PairResolver {
+    token_ids: HashMap<Pubkey, u32>,
+}
+
+impl PairResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cached token id of `mint`
+    pub fn token_id(&self, mint: &Pubkey) -> Option<u32> {
+        self.token_ids.get(mint).copied()
+    }
+
+    /// Instrument trading `mint_x` against `mint_y`, `None` when one of the mints is not
+    /// listed or no instrument pairs them
+    pub fn resolve(
+        &mut self,
+        fetcher: &impl AccountFetcher,
+        mint_x: &Pubkey,
+        mint_y: &Pubkey,
+    ) -> Result<Option<ResolvedPair>> {
+        if mint_x == mint_y {
+            bail!("Pair of identical mints {}", mint_x);
+        }
+
+        self.load_token_ids(fetcher, &[*mint_x, *mint_y])?;
+
+        let (Some(x_id), Some(y_id)) = (self.token_id(mint_x), self.token_id(mint_y)) else {
+            return Ok(None);
+        };
+
+        let candidates = [
+            (*mint_x, *mint_y, Pubkey::new_spot_acc(INSTR, x_id, y_id)),
+            (*mint_y, *mint_x, Pubkey::new_spot_acc(INSTR, y_id, x_id)),
+        ];
+
+        let instr_accs = fetcher.fetch_accounts(&candidates.map(|(_, _, key)| key))?;
+
+        for ((asset_mint, crncy_mint, key), instr_acc) in candidates.into_iter().zip(instr_accs) {
+            let Some(header) = instr_acc.and_then(|acc| instrument_header(&key, &acc)) else {
+                continue;
+            };
+
+            if header.asset_mint != asset_mint {
+                bail!(MarketMismatch::HeaderMint {
+                    expected: asset_mint,
+                    found: header.asset_mint,
+                });
+            }
+
+            if header.crncy_mint != crncy_mint {
+                bail!(MarketMismatch::HeaderMint {
+                    expected: crncy_mint,
+                    found: header.crncy_mint,
+                });
+            }
+
+            return Ok(Some(ResolvedPair {
+                key,
+                asset_mint,
+                crncy_mint,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    /// Reads the token ids of the `mints` not cached yet, mints without a token state stay
+    /// unknown
+    fn load_token_ids(&mut self, fetcher: &impl AccountFetcher, mints: &[Pubkey]) -> Result<()> {
+        let mints = mints
+            .iter()
+            .filter(|mint| !self.token_ids.contains_key(mint))
+            .copied()
+            .collect::<Vec<_>>();
+
+        if mints.is_empty() {
+            return Ok(());
+        }
+
+        let token_state_accs = mints
+            .iter()
+            .map(|mint| mint.new_token_acc())
+            .collect::<Vec<_>>();
+
+        let accounts = fetcher.fetch_accounts(&token_state_accs)?;
+
+        for ((mint, token_state_acc), account) in mints.iter().zip(&token_state_accs).zip(accounts)
+        {
+            let Some(account) = account else {
+                continue;
+            };
+
+            validate_program_account(token_state_acc, &account, TOKEN, size_of::<TokenState>())?;
+            let token_state = bytemuck::pod_read_unaligned::<TokenState>(
+                &account.data[..size_of::<TokenState>()],
+            );
+
+            if token_state.address != *mint {
+                bail!(MarketMismatch::TokenStateMint {
+                    token_state: *token_state_acc,
+                    expected: *mint,
+                    found: token_state.address,
+                });
+            }
+
+            self.token_ids.insert(*mint, token_state.id);
+        }
+
+        Ok(())
+    }
+}
@@ -9,21 +9,28 @@ pub mod tests {
         use drv_models::{
             constants::{DF, nulls::NULL_ORDER, trading_limitations::MAX_PRICE},
             state::{
-                community_account_header::CommunityAccountHeader, instrument::InstrAccountHeader,
-                spots::spot_account_header::SpotTradeAccountHeaderNonGen, token::TokenState,
-                types::PxOrders,
+                community_account_header::CommunityAccountHeader,
+                instrument::InstrAccountHeader,
+                spots::spot_account_header::SpotTradeAccountHeaderNonGen,
+                token::TokenState,
+                types::{
+                    PxOrders,
+                    account_type::{COMMUNITY, INSTR, SPOT_LINES, TOKEN},
+                },
             },
         };
         use jupiter_amm_interface::{
-            AccountMap, Amm, AmmContext, ClockRef, KeyedAccount, QuoteParams, SwapMode,
+            AccountMap, Amm, AmmContext, ClockRef, KeyedAccount, Quote, QuoteParams, SwapMode,
         };
         use solana_sdk::{account::Account, pubkey::Pubkey};
 
         use crate::{
             Deriverse,
+            accounts::TOKEN_PROGRAM_ID,
+            fill::{FillSource, FillStatus, StopReason},
             helper::get_dec_factor,
-            lines_linked_list::Lines,
-            tests::tests::integration_tests::config::{TOKEN_A, TOKEN_B},
+            program_id,
+            tests::tests::integration_tests::config::{TOKEN_A, TOKEN_B, Token},
         };
 
         pub mod config {
@@ -68,6 +75,36 @@ pub mod tests {
             }
         }
 
+        /// Account owned by the program, `data` starts with the `tag` account type
+        fn program_account(tag: u32, mut data: Vec<u8>) -> Account {
+            data[..4].copy_from_slice(&tag.to_le_bytes());
+
+            Account {
+                owner: program_id::id(),
+                ..default_account_with_data(data)
+            }
+        }
+
+        fn token_state_account(token: &Token) -> Account {
+            program_account(
+                TOKEN,
+                bytes_of(&TokenState {
+                    address: token.mint,
+                    id: token.token_id,
+                    mask: token.decs_count,
+                    ..Zeroable::zeroed()
+                })
+                .to_vec(),
+            )
+        }
+
+        fn mint_account() -> Account {
+            Account {
+                owner: TOKEN_PROGRAM_ID,
+                ..default_account_with_data(vec![0; 82])
+            }
+        }
+
         fn build_key_account() -> KeyedAccount {
             let header = InstrAccountHeader {
                 asset_mint: TOKEN_A.mint,
@@ -97,7 +134,7 @@ pub mod tests {
 
                 account_metas.insert(
                     self.accounts_ctx.community_acc,
-                    default_account_with_object(&header),
+                    program_account(COMMUNITY, bytes_of(&header).to_vec()),
                 );
 
                 Ok(())
@@ -106,7 +143,7 @@ pub mod tests {
             pub fn init_order_book(
                 &mut self,
                 account_metas: &mut AccountMap,
-                lines: Lines,
+                lines: Vec<PxOrders>,
                 bid_begin_line: usize,
                 ask_begin_line: usize,
             ) -> Result<()> {
@@ -134,7 +171,7 @@ pub mod tests {
                     .iter()
                     .for_each(|line| data.extend_from_slice(bytes_of(line)));
 
-                account_metas.insert(self.accounts_ctx.lines, default_account_with_data(data));
+                account_metas.insert(self.accounts_ctx.lines, program_account(SPOT_LINES, data));
 
                 Ok(())
             }
@@ -259,24 +296,18 @@ pub mod tests {
 
             accounts_map.insert(
                 deriverse.accounts_ctx.a_token_state_acc,
-                default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
+                token_state_account(&TOKEN_A),
             );
             accounts_map.insert(
                 deriverse.accounts_ctx.b_token_state_acc,
-                default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
+                token_state_account(&TOKEN_B),
             );
             accounts_map.insert(
                 deriverse.accounts_ctx.instr_header,
-                default_account_with_object(deriverse.instr_header.as_ref()),
-            );
-            accounts_map.insert(
-                deriverse.accounts_ctx.a_mint,
-                default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
-            );
-            accounts_map.insert(
-                deriverse.accounts_ctx.b_mint,
-                default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
+                program_account(INSTR, bytes_of(deriverse.instr_header.as_ref()).to_vec()),
             );
+            accounts_map.insert(deriverse.accounts_ctx.a_mint, mint_account());
+            accounts_map.insert(deriverse.accounts_ctx.b_mint, mint_account());
 
             let mut new_deriverse = Deriverse::from_keyed_account(
                 &build_key_account(),
@@ -308,10 +339,89 @@ pub mod tests {
             assert!(new_deriverse.order_book.lines.len() != 0);
         }
 
+        #[cfg(not(feature = "f64-math"))]
+        pub mod test_fixed_point_math {
+            use drv_models::state::types::OrderSide;
+
+            use crate::{
+                amm::DeriverseAmm,
+                math::{isqrt, mul_div, mul_div_ceil},
+            };
+
+            fn amm(a_tokens: i64, b_tokens: i64, dec_factor: i64) -> DeriverseAmm {
+                DeriverseAmm {
+                    k: a_tokens as i128 * b_tokens as i128,
+                    a_tokens,
+                    b_tokens,
+                    dec_factor,
+                }
+            }
+
+            #[test]
+            fn isqrt_rounds_down() {
+                for n in [0u128, 1, 2, 3, 4, 15, 16, 17, 99, 100, 101] {
+                    let root = isqrt(n);
+                    assert!(
+                        root * root <= n && (root + 1) * (root + 1) > n,
+                        "isqrt({})",
+                        n
+                    );
+                }
+
+                let root = u64::MAX as u128;
+                assert_eq!(isqrt(root * root), root);
+                assert_eq!(isqrt(root * root - 1), root - 1);
+                assert_eq!(isqrt(u128::MAX), root);
+            }
+
+            #[test]
+            fn mul_div_is_exact() {
+                assert_eq!(mul_div(7, 3, 2), Some(10));
+                assert_eq!(mul_div_ceil(7, 3, 2), Some(11));
+                assert_eq!(mul_div_ceil(8, 3, 2), Some(12));
+                assert_eq!(mul_div(1, 1, 0), None);
+                assert_eq!(mul_div(-1, 1, 1), None);
+
+                // intermediate product overflows i128
+                let big = 10i128.pow(30);
+                assert_eq!(mul_div(big, big, big), Some(big));
+                assert_eq!(mul_div(big, big, 1), None);
+            }
+
+            #[test]
+            fn large_reserves_do_not_overflow() {
+                // k * dec_factor doesn't fit in i128
+                let amm = amm(10i64.pow(18), 10i64.pow(18), 10i64.pow(9));
+
+                let px = amm.get_amm_px(0, OrderSide::Bid).unwrap();
+                assert_eq!(px, 10i64.pow(9));
+
+                let qty = amm.get_amm_qty(px, OrderSide::Bid).unwrap();
+                assert_eq!(qty, 0);
+
+                let sum = amm.get_reversed_amm_sum(4 * px).unwrap();
+                assert_eq!(sum, 10i64.pow(18));
+            }
+
+            #[test]
+            fn reversed_sum_moves_price_up() {
+                let amm = amm(1_000_000_000_000, 10_000_000_000_000_000, 1_000_000);
+                let px = amm.get_amm_px(0, OrderSide::Ask).unwrap();
+
+                let sum = amm.get_reversed_amm_sum(px + px / 10).unwrap();
+                assert!(sum > 0);
+
+                let new_px = amm.get_reversed_amm_px(sum).unwrap();
+                assert!((new_px - (px + px / 10)).abs() <= 1);
+
+                assert_eq!(amm.get_reversed_amm_sum(px - px / 10).unwrap(), 0);
+            }
+        }
+
         pub mod test_quote_order_book_only {
             use super::*;
 
-            fn init_deriverse() -> Deriverse {
+            pub fn init_deriverse() -> Deriverse {
                 let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
 
                 let mut deriverse = Deriverse::from_keyed_account(
@@ -395,32 +505,20 @@ pub mod tests {
 
                 accounts_map.insert(
                     deriverse.accounts_ctx.a_token_state_acc,
-                    default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
+                    token_state_account(&TOKEN_A),
                 );
                 accounts_map.insert(
                     deriverse.accounts_ctx.b_token_state_acc,
-                    default_account_with_data(
-                        bytes_of(&TokenState {
-                            address: TOKEN_B.mint,
-                            ..Zeroable::zeroed()
-                        })
-                        .to_vec(),
-                    ),
-                );
-                accounts_map.insert(
-                    deriverse.accounts_ctx.a_mint,
-                    default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
-                );
-                accounts_map.insert(
-                    deriverse.accounts_ctx.b_mint,
-                    default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
+                    token_state_account(&TOKEN_B),
                 );
+                accounts_map.insert(deriverse.accounts_ctx.a_mint, mint_account());
+                accounts_map.insert(deriverse.accounts_ctx.b_mint, mint_account());
 
                 deriverse.instr_header.last_px = (10.0 * DF) as i64;
 
                 accounts_map.insert(
                     deriverse.accounts_ctx.instr_header,
-                    default_account_with_object(deriverse.instr_header.as_ref()),
+                    program_account(INSTR, bytes_of(deriverse.instr_header.as_ref()).to_vec()),
                 );
 
                 let mut new_deriverse = Deriverse::from_keyed_account(
@@ -488,6 +586,66 @@ pub mod tests {
                 );
             }
 
+            #[test]
+            fn full_fill_sell_detailed() {
+                let deriverse = init_deriverse();
+
+                let result = deriverse
+                    .quote_detailed(
+                        &QuoteParams {
+                            amount: 200_000,
+                            input_mint: TOKEN_A.mint,
+                            output_mint: TOKEN_B.mint,
+                            swap_mode: SwapMode::ExactIn,
+                        },
+                        None,
+                    )
+                    .unwrap();
+
+                let sources = result
+                    .legs
+                    .iter()
+                    .map(|leg| (leg.source, leg.price, leg.qty))
+                    .collect::<Vec<_>>();
+
+                assert_eq!(
+                    sources,
+                    vec![
+                        (FillSource::Line(1), (10.4 * DF) as i64, 100_000),
+                        (FillSource::Line(0), (10.1 * DF) as i64, 100_000),
+                    ]
+                );
+
+                let sum = result.legs.iter().map(|leg| leg.sum).sum::<i64>();
+                assert_eq!(sum as u64, result.quote.quote.out_amount);
+            }
+
+            #[test]
+            fn full_fill_sell_exact_out() {
+                let deriverse = init_deriverse();
+
+                let expected = (200_000 as f64 / get_dec_factor(TOKEN_A.decs_count as u8) as f64
+                    * (10.4 * 100_000.0 / 200_000.0 + 10.1 * 100_000.0 / 200_000.0)
+                    * get_dec_factor(TOKEN_B.decs_count as u8) as f64)
+                    as u64;
+
+                let result = deriverse
+                    .quote(&QuoteParams {
+                        amount: expected,
+                        input_mint: TOKEN_A.mint,
+                        output_mint: TOKEN_B.mint,
+                        swap_mode: SwapMode::ExactOut,
+                    })
+                    .unwrap();
+
+                let diff = (result.in_amount as i64 - 200_000).abs();
+
+                assert!(
+                    (diff as f64) < 200_000.0 * 0.001,
+                    "Calculations are not presize enough"
+                );
+            }
+
             #[test]
             fn partial_fill_buy() {
                 let deriverse = init_deriverse();
@@ -518,7 +676,7 @@ pub mod tests {
         pub mod test_quote_amm_only {
             use super::*;
 
-            fn init_deriverse() -> Deriverse {
+            pub fn init_deriverse() -> Deriverse {
                 let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
 
                 let mut deriverse = Deriverse::from_keyed_account(
@@ -544,32 +702,20 @@ pub mod tests {
 
                 accounts_map.insert(
                     deriverse.accounts_ctx.a_token_state_acc,
-                    default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
+                    token_state_account(&TOKEN_A),
                 );
                 accounts_map.insert(
                     deriverse.accounts_ctx.b_token_state_acc,
-                    default_account_with_data(
-                        bytes_of(&TokenState {
-                            address: TOKEN_B.mint,
-                            ..Zeroable::zeroed()
-                        })
-                        .to_vec(),
-                    ),
-                );
-                accounts_map.insert(
-                    deriverse.accounts_ctx.a_mint,
-                    default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
-                );
-                accounts_map.insert(
-                    deriverse.accounts_ctx.b_mint,
-                    default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
+                    token_state_account(&TOKEN_B),
                 );
+                accounts_map.insert(deriverse.accounts_ctx.a_mint, mint_account());
+                accounts_map.insert(deriverse.accounts_ctx.b_mint, mint_account());
 
                 deriverse.instr_header.last_px = (10.0 * DF) as i64;
 
                 accounts_map.insert(
                     deriverse.accounts_ctx.instr_header,
-                    default_account_with_object(deriverse.instr_header.as_ref()),
+                    program_account(INSTR, bytes_of(deriverse.instr_header.as_ref()).to_vec()),
                 );
 
                 let mut new_deriverse = Deriverse::from_keyed_account(
@@ -647,12 +793,180 @@ pub mod tests {
                     expected as f64 * 0.000_001
                 );
             }
+
+            #[test]
+            fn sell_exact_out() {
+                let deriverse = init_deriverse();
+
+                let exact_in = deriverse
+                    .quote(&QuoteParams {
+                        amount: 140_000,
+                        input_mint: TOKEN_A.mint,
+                        output_mint: TOKEN_B.mint,
+                        swap_mode: SwapMode::ExactIn,
+                    })
+                    .unwrap();
+
+                let result = deriverse
+                    .quote(&QuoteParams {
+                        amount: exact_in.out_amount,
+                        input_mint: TOKEN_A.mint,
+                        output_mint: TOKEN_B.mint,
+                        swap_mode: SwapMode::ExactOut,
+                    })
+                    .unwrap();
+
+                println!("Result: {:?}", result);
+
+                assert!(result.out_amount >= exact_in.out_amount);
+
+                let diff = (result.in_amount as i64 - exact_in.in_amount as i64).abs();
+
+                assert!(
+                    (diff as f64) < exact_in.in_amount as f64 * 0.001,
+                    "Calculations are not presize enough: diff ({}) > {}",
+                    diff,
+                    exact_in.in_amount as f64 * 0.001
+                );
+            }
+
+            #[test]
+            fn buy_exact_out() {
+                let deriverse = init_deriverse();
+
+                let exact_in = deriverse
+                    .quote(&QuoteParams {
+                        amount: 1_400_000_000,
+                        input_mint: TOKEN_B.mint,
+                        output_mint: TOKEN_A.mint,
+                        swap_mode: SwapMode::ExactIn,
+                    })
+                    .unwrap();
+
+                let result = deriverse
+                    .quote(&QuoteParams {
+                        amount: exact_in.out_amount,
+                        input_mint: TOKEN_B.mint,
+                        output_mint: TOKEN_A.mint,
+                        swap_mode: SwapMode::ExactOut,
+                    })
+                    .unwrap();
+
+                println!("Result: {:?}", result);
+
+                assert_eq!(result.out_amount, exact_in.out_amount);
+
+                let diff = (result.in_amount as i64 - exact_in.in_amount as i64).abs();
+
+                assert!(
+                    (diff as f64) < exact_in.in_amount as f64 * 0.000_001,
+                    "Calculations are not presize enough: diff ({}) > {}",
+                    diff,
+                    exact_in.in_amount as f64 * 0.000_001
+                );
+            }
+        }
+
+        pub mod test_price_limit {
+            use super::*;
+            use crate::params::{DeriverseParams, MaxAge, PartialFillMode, PriceLimit};
+
+            #[test]
+            fn params_from_keyed_account() {
+                let mut keyed_account = build_key_account();
+                keyed_account.params = Some(serde_json::json!({
+                    "priceLimit": { "slippageBps": 50 },
+                    "partialFill": "reject",
+                    "maxAge": { "slots": 150, "idleSeconds": 86400 }
+                }));
+
+                let deriverse = Deriverse::from_keyed_account(
+                    &keyed_account,
+                    &AmmContext {
+                        clock_ref: ClockRef::default(),
+                    },
+                )
+                .unwrap();
+
+                assert_eq!(
+                    deriverse.params,
+                    DeriverseParams {
+                        price_limit: PriceLimit::SlippageBps(50),
+                        partial_fill: PartialFillMode::Reject,
+                        max_age: MaxAge {
+                            slots: Some(150),
+                            seconds: None,
+                            idle_seconds: Some(86400),
+                        },
+                        lookup_table: None,
+                    }
+                );
+            }
+
+            #[test]
+            fn default_limit_px() {
+                let px = (10.0 * DF) as i64;
+
+                assert_eq!(PriceLimit::default().limit_px(px, true), px + (px >> 3));
+                assert_eq!(PriceLimit::default().limit_px(px, false), px - (px >> 3));
+            }
+
+            #[test]
+            fn sell_truncated_by_limit() {
+                let deriverse = test_quote_amm_only::init_deriverse();
+                let amount = 100_000 * get_dec_factor(TOKEN_A.decs_count as u8) as u64;
+
+                let result = deriverse
+                    .quote_with_limit(
+                        &QuoteParams {
+                            amount,
+                            input_mint: TOKEN_A.mint,
+                            output_mint: TOKEN_B.mint,
+                            swap_mode: SwapMode::ExactIn,
+                        },
+                        Some(PriceLimit::SlippageBps(100)),
+                    )
+                    .unwrap();
+
+                println!("Result: {:?}", result);
+
+                assert!(result.truncated_by_limit());
+                assert!(result.quote.in_amount < amount);
+                assert_eq!(
+                    result.status,
+                    FillStatus::Partial {
+                        unfilled: amount - result.quote.in_amount,
+                        reason: StopReason::PriceLimit,
+                    }
+                );
+                assert_eq!(result.limit_price, (9.9 * DF) as i64);
+            }
+
+            #[test]
+            fn small_sell_not_truncated() {
+                let deriverse = test_quote_amm_only::init_deriverse();
+
+                let result = deriverse
+                    .quote_with_limit(
+                        &QuoteParams {
+                            amount: 140_000,
+                            input_mint: TOKEN_A.mint,
+                            output_mint: TOKEN_B.mint,
+                            swap_mode: SwapMode::ExactIn,
+                        },
+                        Some(PriceLimit::SlippageBps(100)),
+                    )
+                    .unwrap();
+
+                assert!(!result.truncated_by_limit());
+                assert_eq!(result.quote.in_amount, 140_000);
+            }
         }
 
         pub mod test_order_book_and_amm {
             use super::*;
 
-            fn init_deriverse() -> Deriverse {
+            pub fn init_deriverse() -> Deriverse {
                 let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
 
                 let mut deriverse = Deriverse::from_keyed_account(
@@ -739,32 +1053,20 @@ pub mod tests {
 
                 accounts_map.insert(
                     deriverse.accounts_ctx.a_token_state_acc,
-                    default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
+                    token_state_account(&TOKEN_A),
                 );
                 accounts_map.insert(
                     deriverse.accounts_ctx.b_token_state_acc,
-                    default_account_with_data(
-                        bytes_of(&TokenState {
-                            address: TOKEN_B.mint,
-                            ..Zeroable::zeroed()
-                        })
-                        .to_vec(),
-                    ),
-                );
-                accounts_map.insert(
-                    deriverse.accounts_ctx.a_mint,
-                    default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
-                );
-                accounts_map.insert(
-                    deriverse.accounts_ctx.b_mint,
-                    default_account_with_data(bytes_of(&TokenState::zeroed()).to_vec()),
+                    token_state_account(&TOKEN_B),
                 );
+                accounts_map.insert(deriverse.accounts_ctx.a_mint, mint_account());
+                accounts_map.insert(deriverse.accounts_ctx.b_mint, mint_account());
 
                 deriverse.instr_header.last_px = (10.0 * DF) as i64;
 
                 accounts_map.insert(
                     deriverse.accounts_ctx.instr_header,
-                    default_account_with_object(deriverse.instr_header.as_ref()),
+                    program_account(INSTR, bytes_of(deriverse.instr_header.as_ref()).to_vec()),
                 );
 
                 let mut new_deriverse = Deriverse::from_keyed_account(
@@ -808,6 +1110,42 @@ pub mod tests {
                 );
             }
 
+            #[test]
+            fn sell_detailed_matches_quote() {
+                let deriverse = init_deriverse();
+
+                let params = QuoteParams {
+                    amount: 140_000,
+                    input_mint: TOKEN_A.mint,
+                    output_mint: TOKEN_B.mint,
+                    swap_mode: SwapMode::ExactIn,
+                };
+
+                let quote = deriverse.quote(&params).unwrap();
+                let detailed = deriverse.quote_detailed(&params, None).unwrap();
+
+                println!("Legs: {:?}", detailed.legs);
+
+                assert!(!detailed.legs.is_empty());
+
+                let qty = detailed.legs.iter().map(|leg| leg.qty).sum::<i64>();
+                let sum = detailed.legs.iter().map(|leg| leg.sum).sum::<i64>();
+                let fees = detailed.legs.iter().map(|leg| leg.fee).sum::<i64>();
+
+                assert_eq!(qty as u64, quote.in_amount);
+                assert_eq!((sum - fees) as u64, quote.out_amount);
+                assert_eq!(fees as u64, quote.fee_amount);
+
+                let amm_qty = detailed
+                    .legs
+                    .iter()
+                    .filter(|leg| leg.source == FillSource::Amm)
+                    .map(|leg| leg.qty)
+                    .sum::<i64>();
+
+                assert_eq!(detailed.amm.a_tokens, deriverse.amm.a_tokens + amm_qty);
+            }
+
             #[test]
             fn buy() {
                 let mut deriverse = init_deriverse();
@@ -848,48 +1186,3092 @@ pub mod tests {
                 );
             }
         }
-    }
 
-    pub mod rpc_tests {
+        /// Quotes that changed when the per-direction line walks were replaced by the
+        /// matching engine, the former results are given next to the current ones
+        pub mod test_matching_engine_changes {
+            use super::*;
+            use crate::amm::DeriverseAmm;
 
-        use ahash::{HashMap, HashMapExt};
-        use bytemuck::bytes_of;
-        use drv_models::state::{
-            client_primary_account_header::ClientPrimaryAccountHeader, token::TokenState,
-            types::account_type::INSTR,
-        };
-        use jupiter_amm_interface::{
-            Amm, AmmContext, ClockRef, KeyedAccount, SwapAndAccountMetas, SwapParams,
-        };
-        use once_cell::sync::Lazy;
-        use solana_client::rpc_client::RpcClient;
-        use solana_sdk::{
-            commitment_config::CommitmentConfig,
-            instruction::Instruction,
-            pubkey::Pubkey,
-            signature::Keypair,
-            signer::{EncodableKey, Signer},
-            transaction::Transaction,
-        };
-        use spl_associated_token_account::get_associated_token_address_with_program_id;
+            #[test]
+            fn exact_in_buy_counts_fees_once() {
+                let mut deriverse = test_quote_order_book_only::init_deriverse();
+                deriverse.instr_header.day_volatility = 0.001;
+                deriverse.fee_rate_factor = 1.0;
 
-        use crate::{
-            Deriverse,
-            custom_sdk::{
-                deposit::{DepositBuildContext, DepositContext},
-                new_spot_order::{NewSpotOrderBuildContext, NewSpotOrderContext},
-                traits::{Context, InstructionBuilder},
-            },
-            from_swap,
-            helper::{Helper, get_dec_factor},
-            program_id,
-            tests::tests::rpc_tests::config::{TOKEN_A, TOKEN_B},
-        };
+                let result = deriverse
+                    .quote(&QuoteParams {
+                        amount: 1_000_000_000,
+                        input_mint: TOKEN_B.mint,
+                        output_mint: TOKEN_A.mint,
+                        swap_mode: SwapMode::ExactIn,
+                    })
+                    .unwrap();
 
-        static RPC: Lazy<RpcClient> = Lazy::new(|| {
-            let url = "https://api.devnet.solana.com";
+                // The fees were added on top of the whole amount, which already covers them:
+                // 1_000_999_000 in for the same output
+                assert_eq!(result.in_amount, 999_999_999);
+                assert_eq!(result.out_amount, 100_891);
+                assert_eq!(result.fee_amount, 999_000);
+            }
 
-            RpcClient::new_with_commitment(url, CommitmentConfig::confirmed())
+            #[test]
+            fn sell_continues_after_amm_reaches_line() {
+                let mut deriverse = test_quote_order_book_only::init_deriverse();
+
+                // Curve above the last bid line at 10.0
+                let (a_tokens, b_tokens) = (1_000_000_000_000, 10_050_000_000_000_000);
+                deriverse.amm = DeriverseAmm {
+                    k: a_tokens as i128 * b_tokens as i128,
+                    a_tokens,
+                    b_tokens,
+                    dec_factor: deriverse.amm.dec_factor,
+                };
+
+                let result = deriverse
+                    .quote_detailed(
+                        &QuoteParams {
+                            amount: 2_497_132_788,
+                            input_mint: TOKEN_A.mint,
+                            output_mint: TOKEN_B.mint,
+                            swap_mode: SwapMode::ExactIn,
+                        },
+                        None,
+                    )
+                    .unwrap();
+
+                // The walk stopped once the AMM reached the line: 2_497_082_788 in for
+                // 25_033_222_116_579 out, 50_000 left unfilled with the line untouched
+                assert_eq!(result.quote.quote.in_amount, 2_497_132_788);
+                assert_eq!(result.quote.quote.out_amount, 25_033_722_116_579);
+
+                let last = result.legs.last().unwrap();
+                assert_eq!((last.source, last.qty), (FillSource::Line(3), 50_000));
+                assert_eq!(result.legs[result.legs.len() - 2].source, FillSource::Amm);
+            }
+        }
+
+        pub mod test_matching_engine {
+            use super::*;
+            use crate::matching::{Budget, MatchingEngine};
+            use drv_models::state::types::OrderSide;
+
+            const ROUNDS: usize = 64;
+
+            /// Deterministic amounts in `1..=max`
+            struct Lcg(u64);
+
+            impl Lcg {
+                fn next(&mut self, max: i64) -> i64 {
+                    self.0 = self
+                        .0
+                        .wrapping_mul(6_364_136_223_846_793_005)
+                        .wrapping_add(1_442_695_040_888_963_407);
+                    1 + (self.0 >> 33) as i64 % max
+                }
+            }
+
+            fn engine(deriverse: &Deriverse, buy: bool) -> MatchingEngine<'_> {
+                let px = deriverse.instr_header.last_px;
+                deriverse.matching_engine(buy, if buy { px * 2 } else { px / 2 })
+            }
+
+            #[test]
+            fn round_trip_is_not_profitable() {
+                let deriverse = test_quote_amm_only::init_deriverse();
+                let mut rng = Lcg(1);
+
+                for _ in 0..ROUNDS {
+                    let qty = rng.next(10_000 * get_dec_factor(TOKEN_A.decs_count as u8));
+
+                    let mut sell = engine(&deriverse, false);
+                    let sold = sell.fill(Budget::Qty(qty)).unwrap();
+
+                    let mut buy = MatchingEngine::new(
+                        sell.amm().clone(),
+                        deriverse.order_book.checked_iter(OrderSide::Ask),
+                        OrderSide::Ask,
+                        deriverse.instr_header.last_px * 2,
+                        0.0,
+                    );
+                    let bought = buy.fill(Budget::Sum(sold.sum)).unwrap();
+
+                    assert!(
+                        bought.qty <= qty,
+                        "Round trip of {} returned {}",
+                        qty,
+                        bought.qty
+                    );
+                }
+            }
+
+            #[test]
+            fn qty_and_sum_budgets_agree() {
+                let deriverse = test_order_book_and_amm::init_deriverse();
+                let mut rng = Lcg(2);
+
+                for buy in [true, false] {
+                    for _ in 0..ROUNDS {
+                        let qty = rng.next(10_000 * get_dec_factor(TOKEN_A.decs_count as u8));
+
+                        let by_qty = engine(&deriverse, buy).fill(Budget::Qty(qty)).unwrap();
+                        let by_sum = engine(&deriverse, buy)
+                            .fill(Budget::Sum(by_qty.sum))
+                            .unwrap();
+
+                        let diff = (by_sum.qty - by_qty.qty).abs();
+
+                        assert!(
+                            diff <= 2 + by_qty.qty / 1_000_000,
+                            "Qty {} vs {} for sum {}",
+                            by_qty.qty,
+                            by_sum.qty,
+                            by_qty.sum
+                        );
+                    }
+                }
+            }
+
+            #[test]
+            fn split_fills_match_single_fill() {
+                let deriverse = test_order_book_and_amm::init_deriverse();
+                let mut rng = Lcg(3);
+
+                for buy in [true, false] {
+                    for _ in 0..ROUNDS {
+                        let first = rng.next(5_000 * get_dec_factor(TOKEN_A.decs_count as u8));
+                        let second = rng.next(5_000 * get_dec_factor(TOKEN_A.decs_count as u8));
+
+                        let single = engine(&deriverse, buy)
+                            .fill(Budget::Qty(first + second))
+                            .unwrap();
+
+                        let mut split = engine(&deriverse, buy);
+                        let head = split.fill(Budget::Qty(first)).unwrap();
+                        let tail = split.fill(Budget::Qty(second)).unwrap();
+
+                        assert_eq!(head.qty + tail.qty, single.qty);
+                        // Every extra leg may round its sum by a unit
+                        let legs = split.legs().len() as i64;
+                        assert!((head.sum + tail.sum - single.sum).abs() <= legs);
+                    }
+                }
+            }
+
+            #[test]
+            fn fills_are_monotonic() {
+                let deriverse = test_order_book_and_amm::init_deriverse();
+                let mut rng = Lcg(4);
+
+                for buy in [true, false] {
+                    let mut amounts = (0..ROUNDS)
+                        .map(|_| rng.next(10_000 * get_dec_factor(TOKEN_A.decs_count as u8)))
+                        .collect::<Vec<_>>();
+                    amounts.sort();
+
+                    let sums = amounts
+                        .iter()
+                        .map(|qty| engine(&deriverse, buy).fill(Budget::Qty(*qty)).unwrap().sum)
+                        .collect::<Vec<_>>();
+
+                    assert!(sums.windows(2).all(|pair| pair[0] <= pair[1]));
+                }
+            }
+        }
+
+        pub mod test_price_impact {
+            use super::*;
+            use drv_models::state::types::OrderSide;
+            use rust_decimal::Decimal;
+
+            #[test]
+            fn amm_spot_price() {
+                let deriverse = test_quote_amm_only::init_deriverse();
+
+                assert_eq!(deriverse.spot_price(), Some(Decimal::from(10)));
+                assert_eq!(deriverse.best_bid_ask(), (None, None));
+                assert_eq!(deriverse.spread(), None);
+            }
+
+            #[test]
+            fn book_best_bid_ask() {
+                let deriverse = test_quote_order_book_only::init_deriverse();
+
+                assert_eq!(deriverse.spot_price(), None);
+                assert_eq!(
+                    deriverse.best_bid_ask(),
+                    (Some(Decimal::new(104, 1)), Some(Decimal::new(99, 1)))
+                );
+                assert_eq!(deriverse.spread(), Some(Decimal::new(-5, 1)));
+            }
+
+            #[test]
+            fn impact_grows_with_size() {
+                let deriverse = test_quote_amm_only::init_deriverse();
+                let unit = get_dec_factor(TOKEN_A.decs_count as u8) as u64;
+
+                for side in [OrderSide::Bid, OrderSide::Ask] {
+                    let small = deriverse.price_impact(unit, side).unwrap();
+                    let large = deriverse.price_impact(10_000 * unit, side).unwrap();
+
+                    println!("Small: {:?}", small);
+                    println!("Large: {:?}", large);
+
+                    assert_eq!(small.spot_price, Decimal::from(10));
+                    assert!(small.impact < large.impact);
+
+                    let marginal = large.marginal_price.unwrap();
+                    match side {
+                        OrderSide::Bid => {
+                            assert!(large.spot_price < large.avg_price);
+                            assert!(large.avg_price < marginal);
+                        }
+                        OrderSide::Ask => {
+                            assert!(large.spot_price > large.avg_price);
+                            assert!(large.avg_price > marginal);
+                        }
+                    }
+                }
+            }
+
+            #[test]
+            fn walk_stops_at_amount() {
+                let deriverse = test_quote_amm_only::init_deriverse();
+                let unit = get_dec_factor(TOKEN_A.decs_count as u8) as u64;
+
+                for side in [OrderSide::Bid, OrderSide::Ask] {
+                    for amount in [1, 777, 123_457 * unit + 3] {
+                        let impact = deriverse.price_impact(amount, side).unwrap();
+                        assert_eq!(impact.qty, amount as i64);
+                    }
+                }
+
+                // beyond the asset reserve the buy drains the AMM instead of failing
+                let drained = deriverse
+                    .price_impact(2_000_000 * unit, OrderSide::Bid)
+                    .unwrap();
+                assert!(drained.qty > (999_999 * unit) as i64);
+                assert!(drained.qty < (1_000_000 * unit) as i64);
+            }
+        }
+
+        pub mod test_depth_ladder {
+            use super::*;
+            use crate::params::PriceLimit;
+            use drv_models::state::types::OrderSide;
+
+            #[test]
+            fn levels_match_quotes() {
+                let deriverse = test_order_book_and_amm::init_deriverse();
+                let amounts = [50_000, 140_000, 250_000, 1_000_000];
+
+                let ladder = deriverse
+                    .depth_ladder(OrderSide::Ask, &amounts, None)
+                    .unwrap();
+
+                println!("Ladder: {:?}", ladder);
+
+                assert_eq!(ladder.len(), amounts.len());
+
+                for level in &ladder {
+                    let quote = deriverse
+                        .quote(&QuoteParams {
+                            amount: level.amount,
+                            input_mint: TOKEN_A.mint,
+                            output_mint: TOKEN_B.mint,
+                            swap_mode: SwapMode::ExactIn,
+                        })
+                        .unwrap();
+
+                    assert_eq!(level.in_amount, quote.in_amount);
+                    // Ladder steps split the AMM legs, each split may round by a unit
+                    assert!((level.out_amount as i64 - quote.out_amount as i64).abs() <= 4);
+                }
+
+                assert!(ladder.windows(2).all(|pair| {
+                    pair[0].out_amount < pair[1].out_amount
+                        && pair[0].avg_price >= pair[1].avg_price
+                        && pair[0].worst_price >= pair[1].worst_price
+                }));
+            }
+
+            #[test]
+            fn ladder_stops_at_limit() {
+                let deriverse = test_quote_amm_only::init_deriverse();
+                let unit = get_dec_factor(TOKEN_A.decs_count as u8) as u64;
+
+                let ladder = deriverse
+                    .depth_ladder(
+                        OrderSide::Ask,
+                        &[unit, 100_000 * unit, 200_000 * unit],
+                        Some(PriceLimit::SlippageBps(100)),
+                    )
+                    .unwrap();
+
+                assert_eq!(ladder.len(), 2);
+                assert_eq!(ladder[0].in_amount, unit);
+                assert!(ladder[1].in_amount < ladder[1].amount);
+            }
+
+            #[test]
+            fn unsorted_amounts() {
+                let deriverse = test_quote_amm_only::init_deriverse();
+
+                assert!(
+                    deriverse
+                        .depth_ladder(OrderSide::Bid, &[2, 1], None)
+                        .is_err()
+                );
+            }
+        }
+
+        pub mod test_max_fillable {
+            use super::*;
+            use crate::params::PriceLimit;
+            use drv_models::state::types::OrderSide;
+
+            #[test]
+            fn order_book_only() {
+                let deriverse = test_quote_order_book_only::init_deriverse();
+
+                assert_eq!(
+                    deriverse.max_fillable(OrderSide::Ask, None).unwrap(),
+                    300_000
+                );
+                assert_eq!(
+                    deriverse.max_fillable(OrderSide::Bid, None).unwrap(),
+                    3_010_000_000
+                );
+            }
+
+            #[test]
+            fn amm_max_fills_without_truncation() {
+                let deriverse = test_quote_amm_only::init_deriverse();
+                let limit = Some(PriceLimit::SlippageBps(100));
+
+                let max = deriverse.max_fillable(OrderSide::Ask, limit).unwrap();
+                println!("Max: {}", max);
+
+                let params = |amount| QuoteParams {
+                    amount,
+                    input_mint: TOKEN_A.mint,
+                    output_mint: TOKEN_B.mint,
+                    swap_mode: SwapMode::ExactIn,
+                };
+
+                let at_max = deriverse.quote_with_limit(&params(max), limit).unwrap();
+
+                assert_eq!(at_max.quote.in_amount, max);
+                assert!(!at_max.truncated_by_limit());
+
+                let above = deriverse
+                    .quote_with_limit(&params(max + max / 100), limit)
+                    .unwrap();
+
+                assert!(above.truncated_by_limit());
+                assert_eq!(above.quote.in_amount, max);
+            }
+        }
+
+        pub mod test_partial_fill {
+            use super::*;
+            use crate::{
+                fill::PartialFillError,
+                params::{DeriverseParams, PartialFillMode},
+            };
+
+            fn oversized_sell() -> QuoteParams {
+                QuoteParams {
+                    amount: 1_000_000,
+                    input_mint: TOKEN_A.mint,
+                    output_mint: TOKEN_B.mint,
+                    swap_mode: SwapMode::ExactIn,
+                }
+            }
+
+            #[test]
+            fn liquidity_exhausted() {
+                let deriverse = test_quote_order_book_only::init_deriverse();
+
+                let result = deriverse.quote_with_limit(&oversized_sell(), None).unwrap();
+
+                assert_eq!(result.quote.in_amount, 300_000);
+                assert_eq!(
+                    result.status,
+                    FillStatus::Partial {
+                        unfilled: 700_000,
+                        reason: StopReason::LiquidityExhausted,
+                    }
+                );
+                assert!(!result.truncated_by_limit());
+            }
+
+            #[test]
+            fn reject_partial_fill() {
+                let mut deriverse = test_quote_order_book_only::init_deriverse();
+                deriverse.params = DeriverseParams {
+                    partial_fill: PartialFillMode::Reject,
+                    ..Default::default()
+                };
+
+                let err = deriverse.quote(&oversized_sell()).unwrap_err();
+
+                assert_eq!(
+                    err.downcast_ref::<PartialFillError>(),
+                    Some(&PartialFillError {
+                        unfilled: 700_000,
+                        reason: StopReason::LiquidityExhausted,
+                    })
+                );
+
+                let result = deriverse
+                    .quote(&QuoteParams {
+                        amount: 140_000,
+                        ..oversized_sell()
+                    })
+                    .unwrap();
+
+                assert_eq!(result.in_amount, 140_000);
+            }
+
+            #[test]
+            fn allow_partial_fill() {
+                let deriverse = test_quote_order_book_only::init_deriverse();
+                assert_eq!(deriverse.params.partial_fill, PartialFillMode::Allow);
+
+                let result = deriverse.quote(&oversized_sell()).unwrap();
+                assert_eq!(result.in_amount, 300_000);
+
+                assert!(deriverse.simulate_swap(&oversized_sell()).is_ok());
+            }
+        }
+
+        pub mod test_simulate_swap {
+            use super::*;
+            use drv_models::state::types::OrderSide;
+
+            fn sell(amount: u64) -> QuoteParams {
+                QuoteParams {
+                    amount,
+                    input_mint: TOKEN_A.mint,
+                    output_mint: TOKEN_B.mint,
+                    swap_mode: SwapMode::ExactIn,
+                }
+            }
+
+            #[test]
+            fn consumed_lines_removed() {
+                let deriverse = test_quote_order_book_only::init_deriverse();
+
+                let (quote, market) = deriverse.simulate_swap(&sell(140_000)).unwrap();
+
+                assert_eq!(quote.in_amount, 140_000);
+
+                let best_bid = market.order_book.begin(OrderSide::Bid).unwrap();
+                assert_eq!(best_bid.price, (10.1 * DF) as i64);
+                assert_eq!(best_bid.qty, 60_000);
+                assert_eq!(best_bid.prev, NULL_ORDER);
+
+                assert_eq!(market.instr_header.best_bid, (10.1 * DF) as i64);
+                assert_eq!(market.instr_header.last_px, (10.1 * DF) as i64);
+
+                // The source market is left untouched
+                assert_eq!(
+                    deriverse.order_book.begin(OrderSide::Bid).unwrap().price,
+                    (10.4 * DF) as i64
+                );
+            }
+
+            fn buy(amount: u64) -> QuoteParams {
+                QuoteParams {
+                    amount,
+                    input_mint: TOKEN_B.mint,
+                    output_mint: TOKEN_A.mint,
+                    swap_mode: SwapMode::ExactIn,
+                }
+            }
+
+            #[test]
+            fn consumed_asks_removed() {
+                let deriverse = test_quote_order_book_only::init_deriverse();
+
+                let (quote, market) = deriverse.simulate_swap(&buy(1_000_000_000)).unwrap();
+
+                // Line 2 is drained for 990_000_000, the rest buys 990 at line 4
+                assert_eq!(quote.in_amount, 1_000_000_000);
+                assert_eq!(quote.out_amount, 100_990);
+
+                assert_eq!(market.order_book.ask_begin_line, 4);
+                let best_ask = market.order_book.begin(OrderSide::Ask).unwrap();
+                assert_eq!(best_ask.qty, 99_010);
+                assert_eq!(best_ask.prev, NULL_ORDER);
+
+                assert_eq!(market.instr_header.best_ask, (10.1 * DF) as i64);
+                assert_eq!(market.instr_header.last_px, (10.1 * DF) as i64);
+
+                // The source market is left untouched
+                assert_eq!(
+                    deriverse.order_book.begin(OrderSide::Ask).unwrap().price,
+                    (9.9 * DF) as i64
+                );
+            }
+
+            #[test]
+            fn dust_left_at_line() {
+                let deriverse = test_quote_order_book_only::init_deriverse();
+
+                let (quote, market) = deriverse.simulate_swap(&buy(990_005_000)).unwrap();
+
+                // The 5_000 left after line 2 is below the price of one unit at line 4
+                assert_eq!(quote.in_amount, 990_005_000);
+                assert_eq!(quote.out_amount, 100_000);
+
+                assert_eq!(market.order_book.ask_begin_line, 4);
+                assert_eq!(
+                    market.order_book.begin(OrderSide::Ask).unwrap().qty,
+                    100_000
+                );
+                assert_eq!(
+                    market.order_book.ask_lines_count,
+                    deriverse.order_book.ask_lines_count - 1
+                );
+
+                assert_eq!(market.instr_header.best_ask, (10.1 * DF) as i64);
+                assert_eq!(market.instr_header.last_px, (9.9 * DF) as i64);
+            }
+
+            #[test]
+            fn back_to_back_swaps() {
+                let deriverse = test_order_book_and_amm::init_deriverse();
+
+                let (first, market) = deriverse.simulate_swap(&sell(140_000)).unwrap();
+                let (second, market) = market.simulate_swap(&sell(140_000)).unwrap();
+                let single = deriverse.quote(&sell(280_000)).unwrap();
+
+                // The first swap drains line 0 and stops inside line 3, the second one takes
+                // the rest of line 3 and then the same AMM leg as the single swap
+                assert_eq!(first.out_amount, 1_410_000_000);
+                assert_eq!(second.out_amount, 1_399_999_937);
+                assert_eq!(first.out_amount + second.out_amount, single.out_amount);
+
+                assert_eq!(market.amm.a_tokens, market.instr_header.asset_tokens);
+                assert_eq!(market.amm.b_tokens, market.instr_header.crncy_tokens);
+                // Last fill is on the AMM, `last_px` is the curve price after it
+                assert_eq!(market.instr_header.last_px, market.amm.spot_px().unwrap());
+                assert!(market.instr_header.last_px < (10.0 * DF) as i64);
+            }
+        }
+
+        pub mod test_order_book {
+            use super::*;
+            use crate::lines_linked_list::OrderBook;
+            use drv_models::state::types::OrderSide;
+
+            #[test]
+            fn clones_share_lines() {
+                let deriverse = test_quote_order_book_only::init_deriverse();
+                let market = deriverse.clone();
+
+                assert!(deriverse.order_book.lines.ptr_eq(&market.order_book.lines));
+
+                let (_, market) = market
+                    .simulate_swap(&QuoteParams {
+                        amount: 140_000,
+                        input_mint: TOKEN_A.mint,
+                        output_mint: TOKEN_B.mint,
+                        swap_mode: SwapMode::ExactIn,
+                    })
+                    .unwrap();
+
+                assert!(!deriverse.order_book.lines.ptr_eq(&market.order_book.lines));
+            }
+
+            fn line(price: f64, next: u32) -> PxOrders {
+                PxOrders {
+                    price: (price * DF) as i64,
+                    qty: 100_000,
+                    next,
+                    prev: NULL_ORDER,
+                    sref: 0,
+                    ..Zeroable::zeroed()
+                }
+            }
+
+            /// Bids at lines 0 and 1, asks at lines 2 and 3
+            fn valid_lines() -> Vec<PxOrders> {
+                vec![
+                    line(10.2, 1),
+                    line(10.0, NULL_ORDER),
+                    line(10.4, 3),
+                    line(10.6, NULL_ORDER),
+                ]
+            }
+
+            fn order_book(lines: &[PxOrders], lines_count: u32) -> OrderBook {
+                let mut data = bytes_of(&SpotTradeAccountHeaderNonGen::zeroed()).to_vec();
+                lines
+                    .iter()
+                    .for_each(|line| data.extend_from_slice(bytes_of(line)));
+
+                let header = InstrAccountHeader {
+                    bid_lines_begin: 0,
+                    ask_lines_begin: 2,
+                    bid_lines_count: lines_count,
+                    ask_lines_count: lines_count,
+                    ..Zeroable::zeroed()
+                };
+
+                OrderBook::new(&header, &default_account_with_data(data))
+            }
+
+            #[test]
+            fn valid_book() {
+                let order_book = order_book(&valid_lines(), 2);
+
+                order_book.validate().unwrap();
+                assert_eq!(order_book.checked_iter(OrderSide::Bid).count(), 2);
+            }
+
+            #[test]
+            fn link_out_of_range() {
+                let mut lines = valid_lines();
+                lines[1].next = 9;
+
+                let order_book = order_book(&lines, 3);
+
+                assert_eq!(order_book.iter_bids().count(), 2);
+
+                let bids = order_book.checked_iter(OrderSide::Bid).collect::<Vec<_>>();
+                assert_eq!(bids.len(), 3);
+                assert!(bids[2].is_err());
+
+                assert!(order_book.validate().is_err());
+            }
+
+            #[test]
+            fn cycle_detected() {
+                let mut lines = valid_lines();
+                lines[1].next = 0;
+
+                let order_book = order_book(&lines, 2);
+
+                assert!(
+                    order_book
+                        .checked_iter(OrderSide::Bid)
+                        .any(|line| line.is_err())
+                );
+                assert!(order_book.validate().is_err());
+            }
+
+            #[test]
+            fn unordered_prices() {
+                let mut lines = valid_lines();
+                lines[3].price = (10.2 * DF) as i64;
+
+                assert!(order_book(&lines, 2).validate().is_err());
+            }
+
+            #[test]
+            fn lines_count_mismatch() {
+                assert!(order_book(&valid_lines(), 3).validate().is_err());
+            }
+
+            fn prices(order_book: &OrderBook, side: OrderSide) -> Vec<i64> {
+                order_book
+                    .checked_iter(side)
+                    .map(|line| line.unwrap().1.price)
+                    .collect()
+            }
+
+            #[test]
+            fn consume_drains_head() {
+                let mut order_book = order_book(&valid_lines(), 2);
+
+                assert!(!order_book.consume(OrderSide::Bid, 0, 40_000).unwrap());
+                assert_eq!(order_book.begin(OrderSide::Bid).unwrap().qty, 60_000);
+
+                assert!(order_book.consume(OrderSide::Bid, 0, 60_000).unwrap());
+                assert_eq!(order_book.bid_begin_line, 1);
+                assert_eq!(order_book.bid_lines_count, 1);
+                assert_eq!(order_book.begin(OrderSide::Bid).unwrap().prev, NULL_ORDER);
+
+                order_book.validate().unwrap();
+
+                // Over-consuming and lines of the other side are rejected
+                assert!(order_book.consume(OrderSide::Bid, 1, 100_001).is_err());
+                assert!(order_book.consume(OrderSide::Bid, 2, 1).is_err());
+            }
+
+            #[test]
+            fn unlink_tail() {
+                let mut order_book = order_book(&valid_lines(), 2);
+
+                order_book.unlink(OrderSide::Ask, 3).unwrap();
+
+                assert_eq!(
+                    prices(&order_book, OrderSide::Ask),
+                    vec![(10.4 * DF) as i64]
+                );
+                assert_eq!(order_book.ask_lines_count, 1);
+                order_book.validate().unwrap();
+            }
+
+            #[test]
+            fn insert_keeps_price_order() {
+                let mut order_book = order_book(&valid_lines(), 2);
+
+                let idx = order_book
+                    .insert(OrderSide::Bid, (10.1 * DF) as i64, 50_000)
+                    .unwrap();
+                assert_eq!(idx, 4);
+                assert_eq!(
+                    prices(&order_book, OrderSide::Bid),
+                    vec![(10.2 * DF) as i64, (10.1 * DF) as i64, (10.0 * DF) as i64]
+                );
+
+                let head = order_book
+                    .insert(OrderSide::Ask, (10.3 * DF) as i64, 50_000)
+                    .unwrap();
+                assert_eq!(order_book.ask_begin_line, head);
+                assert_eq!(order_book.lines.get(2).unwrap().prev, head);
+
+                // Joins the line resting at the same price
+                assert_eq!(
+                    order_book
+                        .insert(OrderSide::Ask, (10.6 * DF) as i64, 50_000)
+                        .unwrap(),
+                    3
+                );
+                assert_eq!(order_book.lines.get(3).unwrap().qty, 150_000);
+
+                assert_eq!(order_book.bid_lines_count, 3);
+                assert_eq!(order_book.ask_lines_count, 3);
+                order_book.validate().unwrap();
+            }
+
+            #[test]
+            fn trailing_partial_line_ignored() {
+                let line = PxOrders {
+                    price: (10.0 * DF) as i64,
+                    qty: 100_000,
+                    next: NULL_ORDER,
+                    prev: NULL_ORDER,
+                    sref: 0,
+                    ..Zeroable::zeroed()
+                };
+
+                let mut data = bytes_of(&SpotTradeAccountHeaderNonGen::zeroed()).to_vec();
+                data.extend_from_slice(bytes_of(&line));
+                data.extend_from_slice(&bytes_of(&line)[..8]);
+
+                let header = InstrAccountHeader {
+                    bid_lines_count: 1,
+                    ..Zeroable::zeroed()
+                };
+                let order_book = OrderBook::new(&header, &default_account_with_data(data));
+
+                assert_eq!(order_book.lines.len(), 1);
+                assert_eq!(
+                    order_book.begin(OrderSide::Bid).map(|line| line.qty),
+                    Some(100_000)
+                );
+            }
+
+            #[test]
+            fn empty_sides_yield_nothing() {
+                let default = OrderBook::default();
+
+                for side in [OrderSide::Bid, OrderSide::Ask] {
+                    assert_eq!(default.checked_iter(side).count(), 0);
+                }
+                default.validate().unwrap();
+
+                let mut no_asks = order_book(&valid_lines(), 2);
+                no_asks.ask_lines_count = 0;
+
+                assert_eq!(no_asks.checked_iter(OrderSide::Ask).count(), 0);
+            }
+
+            #[test]
+            fn quote_fails_on_broken_link() {
+                let mut deriverse = test_quote_order_book_only::init_deriverse();
+                let params = QuoteParams {
+                    amount: 250_000,
+                    input_mint: TOKEN_A.mint,
+                    output_mint: TOKEN_B.mint,
+                    swap_mode: SwapMode::ExactIn,
+                };
+
+                deriverse.quote_detailed(&params, None).unwrap();
+
+                // Line 0 is the second bid, the walk reaches its link after draining it
+                deriverse.order_book.lines.update(0, |line| line.next = 9);
+
+                let err = deriverse.quote_detailed(&params, None).unwrap_err();
+                assert!(err.to_string().contains("out of range"));
+            }
+        }
+
+        pub mod test_orders_book {
+            use super::*;
+            use crate::lines_linked_list::OrderBook;
+            use drv_models::state::{
+                spots::spot_account_header::SPOT_TRADE_ACCOUNT_HEADER_SIZE,
+                types::{
+                    Order, OrderSide,
+                    account_type::{SPOT_ASK_ORDERS, SPOT_BID_ORDERS},
+                },
+            };
+
+            fn order(client_id: u32, qty: i64, next: u32) -> Order {
+                Order {
+                    client_id,
+                    qty,
+                    next,
+                    ..Zeroable::zeroed()
+                }
+            }
+
+            /// Client 7 owns the head of the 10.2 bid line and the whole 10.0 line,
+            /// client 8 rests behind it at 10.2. The ask side is empty
+            pub fn orders() -> Vec<Order> {
+                vec![
+                    order(7, 60_000, 1),
+                    order(8, 40_000, NULL_ORDER),
+                    order(7, 100_000, NULL_ORDER),
+                ]
+            }
+
+            fn orders_account(tag: u32, orders: &[Order]) -> Account {
+                let mut data = bytes_of(&SpotTradeAccountHeaderNonGen::zeroed()).to_vec();
+                orders
+                    .iter()
+                    .for_each(|order| data.extend_from_slice(bytes_of(order)));
+
+                program_account(tag, data)
+            }
+
+            pub fn init_deriverse(orders: &[Order]) -> (Deriverse, AccountMap) {
+                let mut deriverse = test_quote_amm_only::init_deriverse();
+
+                let lines = [
+                    PxOrders {
+                        price: (10.2 * DF) as i64,
+                        qty: 100_000,
+                        next: 1,
+                        prev: NULL_ORDER,
+                        sref: 0,
+                        ..Zeroable::zeroed()
+                    },
+                    PxOrders {
+                        price: (10.0 * DF) as i64,
+                        qty: 100_000,
+                        next: NULL_ORDER,
+                        prev: 0,
+                        sref: 2,
+                        ..Zeroable::zeroed()
+                    },
+                    PxOrders {
+                        price: (10.4 * DF) as i64,
+                        next: NULL_ORDER,
+                        prev: NULL_ORDER,
+                        sref: NULL_ORDER,
+                        ..Zeroable::zeroed()
+                    },
+                ];
+
+                let mut data = bytes_of(&SpotTradeAccountHeaderNonGen::zeroed()).to_vec();
+                lines
+                    .iter()
+                    .for_each(|line| data.extend_from_slice(bytes_of(line)));
+
+                let header = deriverse.instr_header.as_mut();
+                header.bid_lines_begin = 0;
+                header.ask_lines_begin = 2;
+                header.bid_lines_count = 2;
+                header.ask_lines_count = 0;
+                header.best_bid = lines[0].price;
+                header.best_ask = MAX_PRICE;
+
+                deriverse.order_book =
+                    OrderBook::new(&deriverse.instr_header, &default_account_with_data(data));
+
+                let [bid_orders, ask_orders] = deriverse.orders_accounts();
+                let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
+                accounts_map.insert(bid_orders, orders_account(SPOT_BID_ORDERS, orders));
+                accounts_map.insert(ask_orders, orders_account(SPOT_ASK_ORDERS, &[]));
+
+                (deriverse, accounts_map)
+            }
+
+            #[test]
+            fn decodes_line_queues() {
+                let (deriverse, accounts_map) = init_deriverse(&orders());
+                let orders = deriverse.orders_book(&accounts_map).unwrap();
+
+                assert!(orders.asks.is_empty());
+                assert_eq!(
+                    orders
+                        .side(OrderSide::Bid)
+                        .iter()
+                        .map(|order| (order.idx, order.line, order.client_id, order.qty_ahead))
+                        .collect::<Vec<_>>(),
+                    vec![(0, 0, 7, 0), (1, 0, 8, 60_000), (2, 1, 7, 100_000)]
+                );
+                assert_eq!(orders.bids[2].price, (10.0 * DF) as i64);
+                assert_eq!(orders.client_orders(7).count(), 2);
+                assert_eq!(orders.client_orders(9).count(), 0);
+            }
+
+            #[test]
+            fn excludes_client_orders() {
+                let (deriverse, accounts_map) = init_deriverse(&orders());
+                let orders = deriverse.orders_book(&accounts_map).unwrap();
+
+                let market = deriverse.without_client_orders(&orders, 7).unwrap();
+
+                market.order_book.validate().unwrap();
+                assert_eq!(market.order_book.bid_lines_count, 1);
+                assert_eq!(
+                    market
+                        .order_book
+                        .iter_bids()
+                        .map(|(_, line)| (line.price, line.qty))
+                        .collect::<Vec<_>>(),
+                    vec![((10.2 * DF) as i64, 40_000)]
+                );
+                assert_eq!(market.instr_header.bid_lines_count, 1);
+                assert_eq!(market.instr_header.best_bid, (10.2 * DF) as i64);
+                assert_eq!(market.instr_header.best_ask, MAX_PRICE);
+
+                // The source market is left untouched
+                assert_eq!(deriverse.order_book.iter_bids().count(), 2);
+                assert_eq!(
+                    deriverse
+                        .order_book
+                        .begin(OrderSide::Bid)
+                        .map(|line| line.qty),
+                    Some(100_000)
+                );
+            }
+
+            #[test]
+            fn broken_order_links() {
+                let mut out_of_range = orders();
+                out_of_range[1].next = 9;
+
+                let (deriverse, accounts_map) = init_deriverse(&out_of_range);
+                assert!(deriverse.orders_book(&accounts_map).is_err());
+
+                let mut cycle = orders();
+                cycle[1].next = 0;
+
+                let (deriverse, accounts_map) = init_deriverse(&cycle);
+                assert!(deriverse.orders_book(&accounts_map).is_err());
+            }
+
+            #[test]
+            fn queue_adds_up_to_line() {
+                // The 10.0 line holds 100_000, its queue 90_000
+                let mut orders = orders();
+                orders[2].qty = 90_000;
+
+                let (deriverse, accounts_map) = init_deriverse(&orders);
+                assert!(deriverse.orders_book(&accounts_map).is_err());
+            }
+
+            #[test]
+            fn missing_orders_account() {
+                let (deriverse, _) = init_deriverse(&orders());
+                let accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
+
+                assert!(deriverse.orders_book(&accounts_map).is_err());
+            }
+
+            #[test]
+            fn orders_account_checked() {
+                let (deriverse, mut accounts_map) = init_deriverse(&orders());
+                let [bid_orders, _] = deriverse.orders_accounts();
+
+                // The ask orders account passed for the bids
+                accounts_map.insert(bid_orders, orders_account(SPOT_ASK_ORDERS, &orders()));
+                assert!(deriverse.orders_book(&accounts_map).is_err());
+
+                accounts_map.insert(
+                    bid_orders,
+                    Account {
+                        owner: Pubkey::new_unique(),
+                        ..orders_account(SPOT_BID_ORDERS, &orders())
+                    },
+                );
+                assert!(deriverse.orders_book(&accounts_map).is_err());
+
+                accounts_map.insert(
+                    bid_orders,
+                    program_account(SPOT_BID_ORDERS, vec![0; SPOT_TRADE_ACCOUNT_HEADER_SIZE - 1]),
+                );
+                assert!(deriverse.orders_book(&accounts_map).is_err());
+            }
+        }
+
+        pub mod test_candles {
+            use super::*;
+            use crate::candles::{CandleInterval, Candles};
+            use drv_models::state::spots::candles::Candle;
+            use rust_decimal::Decimal;
+
+            fn candle(time: u32, close: f64) -> Candle {
+                Candle {
+                    time,
+                    open: (10.0 * DF) as i64,
+                    high: (close.max(10.0) * DF) as i64,
+                    low: (close.min(10.0) * DF) as i64,
+                    close: (close * DF) as i64,
+                    qty: 1_000,
+                    sum: 10_000,
+                    ..Zeroable::zeroed()
+                }
+            }
+
+            fn candles_account(interval: CandleInterval, candles: &[Candle]) -> Account {
+                let mut data = bytes_of(&SpotTradeAccountHeaderNonGen::zeroed()).to_vec();
+                candles
+                    .iter()
+                    .for_each(|candle| data.extend_from_slice(bytes_of(candle)));
+
+                program_account(interval.tag(), data)
+            }
+
+            fn times(candles: &Candles) -> Vec<u32> {
+                candles.iter().map(|bar| bar.time).collect()
+            }
+
+            #[test]
+            fn wrapped_ring_in_time_order() {
+                // Slots 0 and 1 were overwritten after the ring wrapped
+                let account = candles_account(
+                    CandleInterval::Minute,
+                    &[
+                        candle(300, 10.3),
+                        candle(360, 10.4),
+                        candle(120, 10.0),
+                        candle(180, 10.1),
+                        candle(240, 10.2),
+                    ],
+                );
+
+                let candles = Candles::new(CandleInterval::Minute, &account).unwrap();
+
+                assert_eq!(times(&candles), vec![120, 180, 240, 300, 360]);
+
+                let last = candles.last().unwrap();
+                assert_eq!(last.close, Decimal::new(104, 1));
+                assert_eq!(last.high, Decimal::new(104, 1));
+                assert_eq!(last.low, Decimal::from(10));
+                assert_eq!(last.volume, 1_000);
+            }
+
+            #[test]
+            fn empty_slots_skipped() {
+                let account = candles_account(
+                    CandleInterval::Minute,
+                    &[
+                        candle(60, 10.0),
+                        candle(120, 10.1),
+                        Candle::zeroed(),
+                        Candle::zeroed(),
+                    ],
+                );
+
+                let candles = Candles::new(CandleInterval::Minute, &account).unwrap();
+                assert_eq!(times(&candles), vec![60, 120]);
+
+                let candles = Candles::new(
+                    CandleInterval::Day,
+                    &candles_account(CandleInterval::Day, &[Candle::zeroed()]),
+                )
+                .unwrap();
+                assert!(candles.bars.is_empty());
+                assert!(candles.last().is_none());
+            }
+
+            #[test]
+            fn range() {
+                let account = candles_account(
+                    CandleInterval::FifteenMinutes,
+                    &[candle(900, 10.0), candle(1_800, 10.1), candle(2_700, 10.2)],
+                );
+                let candles = Candles::new(CandleInterval::FifteenMinutes, &account).unwrap();
+
+                assert_eq!(
+                    candles
+                        .range(1_000, 2_700)
+                        .iter()
+                        .map(|bar| bar.time)
+                        .collect::<Vec<_>>(),
+                    vec![1_800]
+                );
+                assert_eq!(candles.range(0, u32::MAX).len(), 3);
+                assert!(candles.range(2_700, 900).is_empty());
+            }
+
+            #[test]
+            fn broken_ring_order() {
+                let account = candles_account(
+                    CandleInterval::Minute,
+                    &[candle(120, 10.0), candle(60, 10.1), candle(180, 10.2)],
+                );
+
+                assert!(Candles::new(CandleInterval::Minute, &account).is_err());
+            }
+
+            #[test]
+            fn candles_from_account_map() {
+                let deriverse = test_quote_amm_only::init_deriverse();
+                let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
+
+                assert!(
+                    deriverse
+                        .candles(CandleInterval::Day, &accounts_map)
+                        .is_err()
+                );
+
+                accounts_map.insert(
+                    deriverse.candles_account(CandleInterval::Day),
+                    candles_account(CandleInterval::Day, &[candle(86_400, 10.0)]),
+                );
+
+                let candles = deriverse
+                    .candles(CandleInterval::Day, &accounts_map)
+                    .unwrap();
+                assert_eq!(candles.interval, CandleInterval::Day);
+                assert_eq!(times(&candles), vec![86_400]);
+                assert_ne!(
+                    deriverse.candles_account(CandleInterval::Day),
+                    deriverse.candles_account(CandleInterval::Minute)
+                );
+            }
+
+            #[test]
+            fn candles_account_checked() {
+                let deriverse = test_quote_amm_only::init_deriverse();
+                let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
+                let day_candles = deriverse.candles_account(CandleInterval::Day);
+
+                // Minute candles at the address of the day candles
+                accounts_map.insert(
+                    day_candles,
+                    candles_account(CandleInterval::Minute, &[candle(86_400, 10.0)]),
+                );
+                assert!(
+                    deriverse
+                        .candles(CandleInterval::Day, &accounts_map)
+                        .is_err()
+                );
+
+                accounts_map.insert(
+                    day_candles,
+                    Account {
+                        owner: Pubkey::new_unique(),
+                        ..candles_account(CandleInterval::Day, &[candle(86_400, 10.0)])
+                    },
+                );
+                assert!(
+                    deriverse
+                        .candles(CandleInterval::Day, &accounts_map)
+                        .is_err()
+                );
+            }
+        }
+
+        pub mod test_client_info {
+            use super::*;
+            use drv_models::state::{
+                client_primary_account_header::ClientPrimaryAccountHeader,
+                spots::client_infos::{SpotClientInfo, SpotClientInfo2},
+                types::{
+                    OrderSide,
+                    account_type::{CLIENT_PRIMARY, SPOT_CLIENT_INFOS, SPOT_CLIENT_INFOS2},
+                },
+            };
+
+            use crate::helper::Helper;
+
+            /// Market of [`test_orders_book`] with clients 8 and 7 in the client infos
+            fn init_deriverse(wallet: &Pubkey, client_id: u32) -> (Deriverse, AccountMap) {
+                let (deriverse, mut accounts_map) =
+                    test_orders_book::init_deriverse(&test_orders_book::orders());
+
+                let infos = [
+                    SpotClientInfo {
+                        client: 8,
+                        ..Zeroable::zeroed()
+                    },
+                    SpotClientInfo {
+                        client: 7,
+                        ..Zeroable::zeroed()
+                    },
+                ];
+                let infos2 = [
+                    SpotClientInfo2 {
+                        fees: 11,
+                        ..Zeroable::zeroed()
+                    },
+                    SpotClientInfo2 {
+                        fees: 25,
+                        ..Zeroable::zeroed()
+                    },
+                ];
+
+                let mut infos_data = bytes_of(&SpotTradeAccountHeaderNonGen::zeroed()).to_vec();
+                infos
+                    .iter()
+                    .for_each(|info| infos_data.extend_from_slice(bytes_of(info)));
+
+                let mut infos2_data = bytes_of(&SpotTradeAccountHeaderNonGen::zeroed()).to_vec();
+                infos2
+                    .iter()
+                    .for_each(|info| infos2_data.extend_from_slice(bytes_of(info)));
+
+                let [client_infos, client_infos2] = deriverse.client_infos_accounts();
+                accounts_map.insert(client_infos, program_account(SPOT_CLIENT_INFOS, infos_data));
+                accounts_map.insert(
+                    client_infos2,
+                    program_account(SPOT_CLIENT_INFOS2, infos2_data),
+                );
+                accounts_map.insert(
+                    wallet.new_client_primary_acc(),
+                    program_account(
+                        CLIENT_PRIMARY,
+                        bytes_of(&ClientPrimaryAccountHeader {
+                            id: client_id,
+                            ..Zeroable::zeroed()
+                        })
+                        .to_vec(),
+                    ),
+                );
+
+                (deriverse, accounts_map)
+            }
+
+            #[test]
+            fn open_orders_and_locked_balances() {
+                let wallet = Pubkey::new_unique();
+                let (deriverse, accounts_map) = init_deriverse(&wallet, 7);
+
+                let info = deriverse
+                    .client_info(&wallet, &accounts_map)
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(info.client_id, 7);
+                assert_eq!(
+                    info.open_orders
+                        .iter()
+                        .map(|(side, order)| (*side, order.idx, order.qty))
+                        .collect::<Vec<_>>(),
+                    vec![(OrderSide::Bid, 0, 60_000), (OrderSide::Bid, 2, 100_000)]
+                );
+
+                // 60_000 at 10.2 and 100_000 at 10.0, dec factor 10^6
+                assert_eq!(info.locked_crncy_tokens, 612_000_000 + 1_000_000_000);
+                assert_eq!(info.locked_asset_tokens, 0);
+                assert_eq!(info.fees_paid, 25);
+            }
+
+            #[test]
+            fn records_matched_by_slot() {
+                let wallet = Pubkey::new_unique();
+                let (deriverse, accounts_map) = init_deriverse(&wallet, 8);
+
+                let info = deriverse
+                    .client_info(&wallet, &accounts_map)
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(info.open_orders.len(), 1);
+                assert_eq!(info.locked_crncy_tokens, 408_000_000);
+                assert_eq!(info.fees_paid, 11);
+            }
+
+            #[test]
+            fn unknown_client() {
+                let wallet = Pubkey::new_unique();
+                let (deriverse, accounts_map) = init_deriverse(&wallet, 9);
+
+                assert!(
+                    deriverse
+                        .client_info(&wallet, &accounts_map)
+                        .unwrap()
+                        .is_none()
+                );
+
+                // No client primary account for this wallet
+                assert!(
+                    deriverse
+                        .client_info(&Pubkey::new_unique(), &accounts_map)
+                        .is_err()
+                );
+            }
+
+            #[test]
+            fn client_infos_accounts_checked() {
+                let wallet = Pubkey::new_unique();
+                let (deriverse, mut accounts_map) = init_deriverse(&wallet, 7);
+                let [client_infos, client_infos2] = deriverse.client_infos_accounts();
+
+                // Both accounts passed in swapped order
+                let infos = accounts_map.remove(&client_infos).unwrap();
+                let infos2 = accounts_map.remove(&client_infos2).unwrap();
+                accounts_map.insert(client_infos, infos2);
+                accounts_map.insert(client_infos2, infos);
+
+                assert!(deriverse.client_info(&wallet, &accounts_map).is_err());
+            }
+
+            #[test]
+            fn client_primary_account_checked() {
+                let wallet = Pubkey::new_unique();
+                let (deriverse, mut accounts_map) = init_deriverse(&wallet, 7);
+                let header = bytes_of(&ClientPrimaryAccountHeader {
+                    id: 7,
+                    ..Zeroable::zeroed()
+                })
+                .to_vec();
+
+                // Not owned by the program
+                accounts_map.insert(
+                    wallet.new_client_primary_acc(),
+                    default_account_with_data(header.clone()),
+                );
+                assert!(deriverse.client_info(&wallet, &accounts_map).is_err());
+
+                accounts_map.insert(
+                    wallet.new_client_primary_acc(),
+                    program_account(SPOT_CLIENT_INFOS, header),
+                );
+                assert!(deriverse.client_info(&wallet, &accounts_map).is_err());
+            }
+        }
+
+        pub mod test_portfolio {
+            use super::*;
+            use drv_models::state::{
+                client_community_account_header::{
+                    ClientCommunityAccountHeader, ClientCommunityRecord,
+                },
+                client_primary_account_header::{AssetRecord, ClientPrimaryAccountHeader},
+                types::account_type::{CLIENT_COMMUNITY, CLIENT_PRIMARY},
+            };
+            use rust_decimal::Decimal;
+
+            use crate::portfolio::{ClientAccounts, ClientPrimary, Portfolio};
+
+            fn primary_account(assets_count: u32, records: &[(u32, i64)]) -> Account {
+                let mut data = bytes_of(&ClientPrimaryAccountHeader {
+                    id: 7,
+                    assets_count,
+                    ..Zeroable::zeroed()
+                })
+                .to_vec();
+                records.iter().for_each(|(asset_id, value)| {
+                    data.extend_from_slice(bytes_of(&AssetRecord {
+                        asset_id: *asset_id,
+                        value: *value,
+                        ..Zeroable::zeroed()
+                    }))
+                });
+
+                program_account(CLIENT_PRIMARY, data)
+            }
+
+            fn community_account(records: &[(u32, i64)]) -> Account {
+                let mut data = bytes_of(&ClientCommunityAccountHeader {
+                    count: records.len() as u32,
+                    ..Zeroable::zeroed()
+                })
+                .to_vec();
+                records
+                    .iter()
+                    .for_each(|(crncy_token_id, dividends_value)| {
+                        data.extend_from_slice(bytes_of(&ClientCommunityRecord {
+                            crncy_token_id: *crncy_token_id,
+                            dividends_value: *dividends_value,
+                            ..Zeroable::zeroed()
+                        }))
+                    });
+
+                program_account(CLIENT_COMMUNITY, data)
+            }
+
+            fn token_states() -> Vec<TokenState> {
+                vec![
+                    TokenState {
+                        id: TOKEN_A.token_id,
+                        address: TOKEN_A.mint,
+                        mask: TOKEN_A.decs_count,
+                        ..Zeroable::zeroed()
+                    },
+                    // Flags above the low byte don't affect the decimals
+                    TokenState {
+                        id: TOKEN_B.token_id,
+                        address: TOKEN_B.mint,
+                        mask: 0x100 | TOKEN_B.decs_count,
+                        ..Zeroable::zeroed()
+                    },
+                ]
+            }
+
+            #[test]
+            fn balances_scaled_by_decimals() {
+                let wallet = Pubkey::new_unique();
+                let accounts = ClientAccounts::new(&wallet);
+
+                let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
+                accounts_map.insert(
+                    accounts.primary,
+                    primary_account(
+                        2,
+                        &[
+                            (TOKEN_B.token_id, 2_500_000_000),
+                            (TOKEN_A.token_id, 1_500_000),
+                        ],
+                    ),
+                );
+                accounts_map.insert(
+                    accounts.community,
+                    community_account(&[(TOKEN_B.token_id, 10_000_000)]),
+                );
+
+                let portfolio = Portfolio::load(&wallet, &accounts_map, &token_states()).unwrap();
+
+                assert_eq!(portfolio.client_id, 7);
+                assert_eq!(
+                    portfolio
+                        .balances
+                        .iter()
+                        .map(|balance| (balance.token_id, balance.deposited, balance.dividends))
+                        .collect::<Vec<_>>(),
+                    vec![
+                        (TOKEN_A.token_id, Decimal::new(15, 1), Decimal::ZERO),
+                        (TOKEN_B.token_id, Decimal::new(25, 1), Decimal::new(1, 2)),
+                    ]
+                );
+                assert_eq!(
+                    portfolio.balance(&TOKEN_B.mint).map(|b| b.token_id),
+                    Some(TOKEN_B.token_id)
+                );
+            }
+
+            #[test]
+            fn community_account_optional() {
+                let wallet = Pubkey::new_unique();
+
+                let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
+                accounts_map.insert(
+                    ClientAccounts::new(&wallet).primary,
+                    primary_account(1, &[(TOKEN_A.token_id, 1_000_000)]),
+                );
+
+                let portfolio = Portfolio::load(&wallet, &accounts_map, &token_states()).unwrap();
+
+                assert_eq!(portfolio.balances.len(), 1);
+                assert_eq!(portfolio.balances[0].deposited, Decimal::ONE);
+                assert!(portfolio.balance(&TOKEN_B.mint).is_none());
+
+                assert!(
+                    Portfolio::load(&Pubkey::new_unique(), &accounts_map, &token_states()).is_err()
+                );
+            }
+
+            #[test]
+            fn program_accounts_checked() {
+                let wallet = Pubkey::new_unique();
+                let accounts = ClientAccounts::new(&wallet);
+
+                let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
+                accounts_map.insert(
+                    accounts.primary,
+                    Account {
+                        owner: Pubkey::new_unique(),
+                        ..primary_account(1, &[(TOKEN_A.token_id, 1_000_000)])
+                    },
+                );
+                assert!(Portfolio::load(&wallet, &accounts_map, &token_states()).is_err());
+
+                accounts_map.insert(
+                    accounts.primary,
+                    primary_account(1, &[(TOKEN_A.token_id, 1_000_000)]),
+                );
+                assert!(Portfolio::load(&wallet, &accounts_map, &token_states()).is_ok());
+
+                // Primary account passed in place of the community one
+                accounts_map.insert(
+                    accounts.community,
+                    primary_account(1, &[(TOKEN_A.token_id, 1_000_000)]),
+                );
+                assert!(Portfolio::load(&wallet, &accounts_map, &token_states()).is_err());
+            }
+
+            #[test]
+            fn invalid_accounts() {
+                // Header expects more records than the account holds
+                assert!(ClientPrimary::new(&primary_account(3, &[(2, 1), (3, 1)])).is_err());
+                assert!(ClientPrimary::new(&default_account_with_data(vec![0; 4])).is_err());
+
+                let primary = ClientPrimary::new(&primary_account(1, &[(5, 1)])).unwrap();
+                assert!(Portfolio::new(&primary, None, &token_states()).is_err());
+
+                // Decimals beyond the precision of a Decimal
+                let mut token_states = token_states();
+                token_states[0].mask = 29;
+                let primary =
+                    ClientPrimary::new(&primary_account(1, &[(TOKEN_A.token_id, 1)])).unwrap();
+                assert!(Portfolio::new(&primary, None, &token_states).is_err());
+            }
+        }
+
+        pub mod test_account_validation {
+            use super::*;
+            use crate::{
+                AccountsHolder,
+                accounts::{AccountError, validate_program_account},
+            };
+
+            fn account_error(err: anyhow::Error) -> AccountError {
+                *err.downcast_ref::<AccountError>().unwrap()
+            }
+
+            fn keyed_deriverse() -> Deriverse {
+                Deriverse::from_keyed_account(
+                    &build_key_account(),
+                    &AmmContext {
+                        clock_ref: ClockRef::default(),
+                    },
+                )
+                .unwrap()
+            }
+
+            #[test]
+            fn validate_owner_size_and_tag() {
+                let address = Pubkey::new_unique();
+                let data = bytes_of(&InstrAccountHeader::zeroed()).to_vec();
+
+                let account = program_account(INSTR, data.clone());
+                validate_program_account(&address, &account, INSTR, data.len()).unwrap();
+
+                assert_eq!(
+                    validate_program_account(&address, &account, COMMUNITY, data.len()),
+                    Err(AccountError::InvalidTag {
+                        address,
+                        tag: INSTR,
+                        expected: COMMUNITY,
+                    })
+                );
+                assert_eq!(
+                    validate_program_account(&address, &account, INSTR, data.len() + 1),
+                    Err(AccountError::TooSmall {
+                        address,
+                        len: data.len(),
+                        expected: data.len() + 1,
+                    })
+                );
+                assert_eq!(
+                    validate_program_account(
+                        &address,
+                        &default_account_with_data(data.clone()),
+                        INSTR,
+                        data.len()
+                    ),
+                    Err(AccountError::InvalidOwner {
+                        address,
+                        owner: solana_sdk::system_program::id(),
+                    })
+                );
+
+                // Too short to hold the tag itself
+                let account = Account {
+                    owner: program_id::id(),
+                    ..default_account_with_data(vec![0; 2])
+                };
+                assert_eq!(
+                    validate_program_account(&address, &account, INSTR, 0),
+                    Err(AccountError::TooSmall {
+                        address,
+                        len: 2,
+                        expected: 4,
+                    })
+                );
+            }
+
+            #[test]
+            fn short_account_is_an_error() {
+                let address = Pubkey::new_unique();
+
+                let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
+                accounts_map.insert(address, program_account(TOKEN, vec![0; 8]));
+
+                let err = accounts_map
+                    .from_account::<TokenState>(&address)
+                    .unwrap_err();
+                assert!(matches!(
+                    account_error(err),
+                    AccountError::TooSmall { len: 8, .. }
+                ));
+
+                let err = accounts_map
+                    .from_program_account::<TokenState>(&address, TOKEN)
+                    .unwrap_err();
+                assert!(matches!(
+                    account_error(err),
+                    AccountError::TooSmall { len: 8, .. }
+                ));
+
+                let err = accounts_map
+                    .from_account::<TokenState>(&Pubkey::new_unique())
+                    .unwrap_err();
+                assert!(matches!(account_error(err), AccountError::Missing { .. }));
+
+                let mut keyed_account = build_key_account();
+                keyed_account.account.data.truncate(16);
+                assert!(
+                    Deriverse::from_keyed_account(
+                        &keyed_account,
+                        &AmmContext {
+                            clock_ref: ClockRef::default(),
+                        },
+                    )
+                    .is_err()
+                );
+            }
+
+            #[test]
+            fn update_rejects_foreign_accounts() {
+                let mut deriverse = keyed_deriverse();
+                let instr_header = deriverse.accounts_ctx.instr_header;
+                let data = bytes_of(deriverse.instr_header.as_ref()).to_vec();
+
+                let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
+                let err = deriverse.update(&accounts_map).unwrap_err();
+                assert_eq!(
+                    account_error(err),
+                    AccountError::Missing {
+                        address: instr_header
+                    }
+                );
+
+                accounts_map.insert(instr_header, default_account_with_data(data.clone()));
+                let err = deriverse.update(&accounts_map).unwrap_err();
+                assert!(matches!(
+                    account_error(err),
+                    AccountError::InvalidOwner { .. }
+                ));
+
+                accounts_map.insert(instr_header, program_account(SPOT_LINES, data.clone()));
+                let err = deriverse.update(&accounts_map).unwrap_err();
+                assert!(matches!(
+                    account_error(err),
+                    AccountError::InvalidTag { tag, .. } if tag == SPOT_LINES
+                ));
+
+                accounts_map.insert(instr_header, program_account(INSTR, data[..64].to_vec()));
+                let err = deriverse.update(&accounts_map).unwrap_err();
+                assert!(matches!(
+                    account_error(err),
+                    AccountError::TooSmall { len: 64, .. }
+                ));
+            }
+        }
+
+        pub mod test_market_consistency {
+            use super::*;
+            use crate::accounts::{AccountError, MarketMismatch};
+
+            /// Consistent accounts of an AMM-only market and a market not yet updated
+            pub fn init_accounts() -> (Deriverse, AccountMap) {
+                let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
+
+                let mut deriverse = Deriverse::from_keyed_account(
+                    &build_key_account(),
+                    &AmmContext {
+                        clock_ref: ClockRef::default(),
+                    },
+                )
+                .unwrap();
+
+                deriverse
+                    .init_community_header(0, &mut accounts_map)
+                    .unwrap();
+                deriverse.init_amm(1_000_000, 10_000_000);
+                deriverse
+                    .init_order_book(&mut accounts_map, vec![], 0, 0)
+                    .unwrap();
+
+                accounts_map.insert(
+                    deriverse.accounts_ctx.a_token_state_acc,
+                    token_state_account(&TOKEN_A),
+                );
+                accounts_map.insert(
+                    deriverse.accounts_ctx.b_token_state_acc,
+                    token_state_account(&TOKEN_B),
+                );
+                accounts_map.insert(
+                    deriverse.accounts_ctx.instr_header,
+                    program_account(INSTR, bytes_of(deriverse.instr_header.as_ref()).to_vec()),
+                );
+                accounts_map.insert(deriverse.accounts_ctx.a_mint, mint_account());
+                accounts_map.insert(deriverse.accounts_ctx.b_mint, mint_account());
+
+                let market = Deriverse::from_keyed_account(
+                    &build_key_account(),
+                    &AmmContext {
+                        clock_ref: ClockRef::default(),
+                    },
+                )
+                .unwrap();
+
+                (market, accounts_map)
+            }
+
+            fn mismatch(err: anyhow::Error) -> MarketMismatch {
+                *err.downcast_ref::<MarketMismatch>().unwrap()
+            }
+
+            #[test]
+            fn consistent_accounts() {
+                let (mut market, accounts_map) = init_accounts();
+
+                market.update(&accounts_map).unwrap();
+
+                assert_eq!(market.get_reserve_mints(), vec![TOKEN_A.mint, TOKEN_B.mint]);
+                assert_eq!(market.a_program_id, TOKEN_PROGRAM_ID);
+            }
+
+            #[test]
+            fn swapped_token_states() {
+                let (mut market, mut accounts_map) = init_accounts();
+                market.update(&accounts_map).unwrap();
+
+                let a_token_state_acc = market.accounts_ctx.a_token_state_acc;
+                accounts_map.insert(a_token_state_acc, token_state_account(&TOKEN_B));
+
+                let err = market.update(&accounts_map).unwrap_err();
+                assert_eq!(
+                    mismatch(err),
+                    MarketMismatch::TokenStateMint {
+                        token_state: a_token_state_acc,
+                        expected: TOKEN_A.mint,
+                        found: TOKEN_B.mint,
+                    }
+                );
+
+                // The failed update leaves the market as it was
+                assert_eq!(market.get_reserve_mints(), vec![TOKEN_A.mint, TOKEN_B.mint]);
+            }
+
+            #[test]
+            fn token_id_mismatch() {
+                let (mut market, mut accounts_map) = init_accounts();
+
+                let b_token_state_acc = market.accounts_ctx.b_token_state_acc;
+                accounts_map.insert(
+                    b_token_state_acc,
+                    program_account(
+                        TOKEN,
+                        bytes_of(&TokenState {
+                            address: TOKEN_B.mint,
+                            id: 42,
+                            ..Zeroable::zeroed()
+                        })
+                        .to_vec(),
+                    ),
+                );
+
+                let err = market.update(&accounts_map).unwrap_err();
+                assert_eq!(
+                    mismatch(err),
+                    MarketMismatch::TokenStateId {
+                        token_state: b_token_state_acc,
+                        expected: TOKEN_B.token_id,
+                        found: 42,
+                    }
+                );
+            }
+
+            #[test]
+            fn header_mint_moved() {
+                let (mut market, mut accounts_map) = init_accounts();
+
+                let header = InstrAccountHeader {
+                    asset_mint: Pubkey::new_unique(),
+                    ..*market.instr_header
+                };
+                accounts_map.insert(
+                    market.accounts_ctx.instr_header,
+                    program_account(INSTR, bytes_of(&header).to_vec()),
+                );
+
+                let err = market.update(&accounts_map).unwrap_err();
+                assert_eq!(
+                    mismatch(err),
+                    MarketMismatch::HeaderMint {
+                        expected: TOKEN_A.mint,
+                        found: header.asset_mint,
+                    }
+                );
+            }
+
+            #[test]
+            fn mint_not_owned_by_token_program() {
+                let (mut market, mut accounts_map) = init_accounts();
+
+                let b_mint = market.accounts_ctx.b_mint;
+                accounts_map.insert(b_mint, default_account_with_data(vec![0; 82]));
+
+                let err = market.update(&accounts_map).unwrap_err();
+                assert_eq!(
+                    *err.downcast_ref::<AccountError>().unwrap(),
+                    AccountError::InvalidOwner {
+                        address: b_mint,
+                        owner: solana_sdk::system_program::id(),
+                    }
+                );
+            }
+        }
+
+        pub mod test_staleness {
+            use super::*;
+            use crate::{
+                clock::{ClockStamp, StaleState},
+                params::{DeriverseParams, MaxAge},
+            };
+            use drv_models::state::types::OrderSide;
+            use std::sync::atomic::Ordering;
+
+            fn set_clock(clock_ref: &ClockRef, slot: u64, unix_timestamp: i64) {
+                clock_ref.slot.store(slot, Ordering::Relaxed);
+                clock_ref
+                    .unix_timestamp
+                    .store(unix_timestamp, Ordering::Relaxed);
+            }
+
+            /// AMM-only market following `clock_ref`, last traded at `last_time`
+            pub fn init_market(
+                clock_ref: &ClockRef,
+                max_age: MaxAge,
+                last_time: u32,
+            ) -> (Deriverse, AccountMap) {
+                let (market, mut accounts_map) = test_market_consistency::init_accounts();
+
+                // Last price at the AMM spot price, 10_000_000 crncy per 1_000_000 asset
+                let header = InstrAccountHeader {
+                    last_time,
+                    last_px: (0.01 * DF) as i64,
+                    ..*market.instr_header
+                };
+                accounts_map.insert(
+                    market.accounts_ctx.instr_header,
+                    program_account(INSTR, bytes_of(&header).to_vec()),
+                );
+
+                let mut market = Deriverse::from_keyed_account(
+                    &build_key_account(),
+                    &AmmContext {
+                        clock_ref: clock_ref.clone(),
+                    },
+                )
+                .unwrap();
+                market.params = DeriverseParams {
+                    max_age,
+                    ..Default::default()
+                };
+
+                (market, accounts_map)
+            }
+
+            fn sell() -> QuoteParams {
+                QuoteParams {
+                    amount: 1_000,
+                    input_mint: TOKEN_A.mint,
+                    output_mint: TOKEN_B.mint,
+                    swap_mode: SwapMode::ExactIn,
+                }
+            }
+
+            fn stale_error(market: &Deriverse) -> StaleState {
+                *market
+                    .quote(&sell())
+                    .unwrap_err()
+                    .downcast_ref::<StaleState>()
+                    .unwrap()
+            }
+
+            #[test]
+            fn outdated_update() {
+                let clock_ref = ClockRef::default();
+                let max_age = MaxAge {
+                    slots: Some(10),
+                    seconds: Some(30),
+                    ..Default::default()
+                };
+                let (mut market, accounts_map) = init_market(&clock_ref, max_age, 0);
+
+                assert_eq!(stale_error(&market), StaleState::NeverUpdated);
+
+                set_clock(&clock_ref, 100, 1_000);
+                market.update(&accounts_map).unwrap();
+
+                assert_eq!(
+                    market.last_update(),
+                    Some(ClockStamp {
+                        slot: 100,
+                        unix_timestamp: 1_000,
+                    })
+                );
+
+                set_clock(&clock_ref, 110, 1_030);
+                assert!(market.staleness().is_none());
+                assert!(market.quote(&sell()).is_ok());
+
+                set_clock(&clock_ref, 111, 1_004);
+                assert_eq!(
+                    stale_error(&market),
+                    StaleState::Outdated {
+                        slots: 11,
+                        seconds: 4,
+                    }
+                );
+
+                set_clock(&clock_ref, 105, 1_031);
+                assert_eq!(
+                    market.staleness(),
+                    Some(StaleState::Outdated {
+                        slots: 5,
+                        seconds: 31,
+                    })
+                );
+                assert!(!market.is_active());
+
+                // A fresh update makes the market quotable again
+                market.update(&accounts_map).unwrap();
+                assert!(market.quote(&sell()).is_ok());
+            }
+
+            #[test]
+            fn analytics_refused_when_stale() {
+                let clock_ref = ClockRef::default();
+                let max_age = MaxAge {
+                    slots: Some(10),
+                    ..Default::default()
+                };
+                let (mut market, accounts_map) = init_market(&clock_ref, max_age, 0);
+
+                let stale = |err: anyhow::Error| *err.downcast_ref::<StaleState>().unwrap();
+
+                assert_eq!(
+                    stale(market.price_impact(1_000, OrderSide::Ask).unwrap_err()),
+                    StaleState::NeverUpdated
+                );
+                assert_eq!(
+                    stale(market.max_fillable(OrderSide::Ask, None).unwrap_err()),
+                    StaleState::NeverUpdated
+                );
+                assert_eq!(
+                    stale(
+                        market
+                            .depth_ladder(OrderSide::Ask, &[1_000], None)
+                            .unwrap_err()
+                    ),
+                    StaleState::NeverUpdated
+                );
+
+                market.update(&accounts_map).unwrap();
+
+                assert!(market.price_impact(1_000, OrderSide::Ask).is_ok());
+                assert!(market.max_fillable(OrderSide::Ask, None).is_ok());
+                assert!(market.depth_ladder(OrderSide::Ask, &[1_000], None).is_ok());
+            }
+
+            #[test]
+            fn frozen_market() {
+                let clock_ref = ClockRef::default();
+                let max_age = MaxAge {
+                    idle_seconds: Some(86_400),
+                    ..Default::default()
+                };
+
+                set_clock(&clock_ref, 100, 100_000);
+                let (mut market, accounts_map) = init_market(&clock_ref, max_age, 10_000);
+                market.update(&accounts_map).unwrap();
+
+                assert_eq!(
+                    stale_error(&market),
+                    StaleState::Frozen {
+                        idle_seconds: 90_000
+                    }
+                );
+
+                // Instruments that never traded aren't considered frozen
+                let (mut market, accounts_map) = init_market(&clock_ref, max_age, 0);
+                market.update(&accounts_map).unwrap();
+                assert!(market.staleness().is_none());
+            }
+
+            #[test]
+            fn unlimited_by_default() {
+                let now = ClockStamp {
+                    slot: u64::MAX,
+                    unix_timestamp: i64::MAX,
+                };
+
+                assert!(MaxAge::default().check(now, None, 1).is_none());
+            }
+        }
+
+        pub mod test_transfer_fee {
+            use super::*;
+            use crate::{
+                accounts::TOKEN_2022_PROGRAM_ID, params::MaxAge, transfer_fee::TransferFees,
+            };
+            use drv_models::state::types::OrderSide;
+            use spl_token_2022::{
+                extension::{
+                    BaseStateWithExtensionsMut, ExtensionType, StateWithExtensionsMut,
+                    transfer_fee::{TransferFee, TransferFeeConfig},
+                },
+                state::Mint,
+            };
+            use std::sync::atomic::Ordering;
+
+            /// 1% fee up to epoch 5, 2% from then on
+            fn token_2022_mint_account() -> Account {
+                let len = ExtensionType::try_calculate_account_len::<Mint>(&[
+                    ExtensionType::TransferFeeConfig,
+                ])
+                .unwrap();
+                let mut data = vec![0; len];
+
+                let mut mint =
+                    StateWithExtensionsMut::<Mint>::unpack_uninitialized(&mut data).unwrap();
+                let config = mint.init_extension::<TransferFeeConfig>(true).unwrap();
+                config.older_transfer_fee = TransferFee {
+                    epoch: 0.into(),
+                    maximum_fee: u64::MAX.into(),
+                    transfer_fee_basis_points: 100.into(),
+                };
+                config.newer_transfer_fee = TransferFee {
+                    epoch: 5.into(),
+                    maximum_fee: u64::MAX.into(),
+                    transfer_fee_basis_points: 200.into(),
+                };
+                mint.base = Mint {
+                    decimals: TOKEN_A.decs_count as u8,
+                    is_initialized: true,
+                    ..Default::default()
+                };
+                mint.pack_base();
+                mint.init_account_type().unwrap();
+
+                Account {
+                    owner: TOKEN_2022_PROGRAM_ID,
+                    ..default_account_with_data(data)
+                }
+            }
+
+            /// Same market with and without the asset transfer fee
+            fn init_markets(clock_ref: &ClockRef) -> (Deriverse, Deriverse) {
+                let (mut plain, accounts_map) =
+                    test_staleness::init_market(clock_ref, MaxAge::default(), 0);
+                plain.update(&accounts_map).unwrap();
+
+                let (mut market, mut accounts_map) =
+                    test_staleness::init_market(clock_ref, MaxAge::default(), 0);
+                accounts_map.insert(market.accounts_ctx.a_mint, token_2022_mint_account());
+                market.update(&accounts_map).unwrap();
+
+                (plain, market)
+            }
+
+            fn quote(
+                market: &Deriverse,
+                input: Pubkey,
+                output: Pubkey,
+                swap_mode: SwapMode,
+                amount: u64,
+            ) -> Quote {
+                market
+                    .quote(&QuoteParams {
+                        amount,
+                        input_mint: input,
+                        output_mint: output,
+                        swap_mode,
+                    })
+                    .unwrap()
+            }
+
+            #[test]
+            fn parses_fee_schedule() {
+                let fees = TransferFees::new(&token_2022_mint_account()).unwrap();
+
+                assert_eq!(fees.fee(0, 10_000).unwrap(), 100);
+                assert_eq!(fees.fee(5, 10_000).unwrap(), 200);
+                assert_eq!(fees.net(4, 10_000).unwrap(), 9_900);
+                assert_eq!(fees.gross(0, 9_900).unwrap(), 10_000);
+
+                let legacy = TransferFees::new(&mint_account()).unwrap();
+                assert!(legacy.is_empty());
+                assert_eq!(legacy.gross(0, 9_900).unwrap(), 9_900);
+            }
+
+            #[test]
+            fn inbound_fee_on_sell() {
+                let clock_ref = ClockRef::default();
+                let (plain, market) = init_markets(&clock_ref);
+
+                let fee_quote = quote(
+                    &market,
+                    TOKEN_A.mint,
+                    TOKEN_B.mint,
+                    SwapMode::ExactIn,
+                    10_000,
+                );
+                let plain_quote =
+                    quote(&plain, TOKEN_A.mint, TOKEN_B.mint, SwapMode::ExactIn, 9_900);
+
+                assert_eq!(fee_quote.out_amount, plain_quote.out_amount);
+                assert_eq!(fee_quote.in_amount, 10_000);
+            }
+
+            #[test]
+            fn outbound_fee_on_buy() {
+                let clock_ref = ClockRef::default();
+                let (plain, market) = init_markets(&clock_ref);
+
+                let fee_quote = quote(
+                    &market,
+                    TOKEN_B.mint,
+                    TOKEN_A.mint,
+                    SwapMode::ExactIn,
+                    10_000,
+                );
+                let plain_quote = quote(
+                    &plain,
+                    TOKEN_B.mint,
+                    TOKEN_A.mint,
+                    SwapMode::ExactIn,
+                    10_000,
+                );
+
+                let fee = plain_quote.out_amount.div_ceil(100);
+                assert_eq!(fee_quote.out_amount, plain_quote.out_amount - fee);
+                assert_eq!(fee_quote.in_amount, plain_quote.in_amount);
+
+                // The newer schedule applies from its epoch on
+                clock_ref.epoch.store(5, Ordering::Relaxed);
+
+                let fee_quote = quote(
+                    &market,
+                    TOKEN_B.mint,
+                    TOKEN_A.mint,
+                    SwapMode::ExactIn,
+                    10_000,
+                );
+                let fee = plain_quote.out_amount.div_ceil(50);
+                assert_eq!(fee_quote.out_amount, plain_quote.out_amount - fee);
+            }
+
+            #[test]
+            fn exact_out_covers_fees() {
+                let clock_ref = ClockRef::default();
+                let (plain, market) = init_markets(&clock_ref);
+                let fees = TransferFees::new(&token_2022_mint_account()).unwrap();
+
+                // Sent asset is grossed up so that the matched qty reaches the market
+                let fee_quote = quote(
+                    &market,
+                    TOKEN_A.mint,
+                    TOKEN_B.mint,
+                    SwapMode::ExactOut,
+                    10_000,
+                );
+                let plain_quote = quote(
+                    &plain,
+                    TOKEN_A.mint,
+                    TOKEN_B.mint,
+                    SwapMode::ExactOut,
+                    10_000,
+                );
+
+                assert_eq!(fee_quote.out_amount, plain_quote.out_amount);
+                assert_eq!(
+                    fee_quote.in_amount,
+                    fees.gross(0, plain_quote.in_amount).unwrap()
+                );
+
+                // Received asset is matched gross so that the requested qty lands
+                let fee_quote = quote(
+                    &market,
+                    TOKEN_B.mint,
+                    TOKEN_A.mint,
+                    SwapMode::ExactOut,
+                    9_900,
+                );
+                let plain_quote = quote(
+                    &plain,
+                    TOKEN_B.mint,
+                    TOKEN_A.mint,
+                    SwapMode::ExactOut,
+                    10_000,
+                );
+
+                assert_eq!(fee_quote.out_amount, 9_900);
+                assert_eq!(fee_quote.in_amount, plain_quote.in_amount);
+            }
+
+            #[test]
+            fn max_fillable_covers_fees() {
+                let clock_ref = ClockRef::default();
+                let (_, market) = init_markets(&clock_ref);
+
+                let max = market.max_fillable(OrderSide::Ask, None).unwrap();
+                let params = |amount| QuoteParams {
+                    amount,
+                    input_mint: TOKEN_A.mint,
+                    output_mint: TOKEN_B.mint,
+                    swap_mode: SwapMode::ExactIn,
+                };
+
+                let at_max = market.quote_with_limit(&params(max), None).unwrap();
+                assert_eq!(at_max.quote.in_amount, max);
+                assert_eq!(at_max.status, FillStatus::Filled);
+
+                let above = market
+                    .quote_with_limit(&params(max + max / 100), None)
+                    .unwrap();
+                assert!(above.truncated_by_limit());
+                assert_eq!(above.quote.in_amount, max);
+            }
+
+            #[test]
+            fn depth_ladder_matches_quotes() {
+                let clock_ref = ClockRef::default();
+                let (_, market) = init_markets(&clock_ref);
+
+                for (side, input, output, amounts) in [
+                    (
+                        OrderSide::Ask,
+                        TOKEN_A.mint,
+                        TOKEN_B.mint,
+                        [10_000, 30_000, 60_000],
+                    ),
+                    (
+                        OrderSide::Bid,
+                        TOKEN_B.mint,
+                        TOKEN_A.mint,
+                        [100_000, 300_000, 600_000],
+                    ),
+                ] {
+                    let ladder = market.depth_ladder(side, &amounts, None).unwrap();
+                    assert_eq!(ladder.len(), amounts.len());
+
+                    for level in &ladder {
+                        let quote = quote(&market, input, output, SwapMode::ExactIn, level.amount);
+
+                        assert_eq!(level.in_amount, quote.in_amount);
+                        assert_eq!(level.out_amount, quote.out_amount);
+                    }
+                }
+            }
+
+            #[test]
+            fn price_impact_of_sent_qty() {
+                let clock_ref = ClockRef::default();
+                let (plain, market) = init_markets(&clock_ref);
+
+                // 1% of the sold asset is lost on the way in
+                let fee_impact = market.price_impact(10_000, OrderSide::Ask).unwrap();
+                let plain_impact = plain.price_impact(9_900, OrderSide::Ask).unwrap();
+
+                assert_eq!(fee_impact.qty, 10_000);
+                assert_eq!(fee_impact.avg_price, plain_impact.avg_price);
+
+                // The bought asset is matched gross so that the requested qty lands
+                let fee_impact = market.price_impact(9_900, OrderSide::Bid).unwrap();
+                let plain_impact = plain.price_impact(10_000, OrderSide::Bid).unwrap();
+
+                assert_eq!(fee_impact.qty, 9_900);
+                assert_eq!(fee_impact.avg_price, plain_impact.avg_price);
+            }
+        }
+
+        pub mod test_transfer_hook {
+            use super::*;
+            use crate::{accounts::TOKEN_2022_PROGRAM_ID, params::MaxAge};
+            use jupiter_amm_interface::{
+                AccountsType, RemainingAccountsInfo, RemainingAccountsSlice, Swap,
+                SwapAndAccountMetas, SwapParams,
+            };
+            use solana_sdk::instruction::AccountMeta;
+            use spl_pod::optional_keys::OptionalNonZeroPubkey;
+            use spl_tlv_account_resolution::{
+                account::ExtraAccountMeta, seeds::Seed, state::ExtraAccountMetaList,
+            };
+            use spl_token_2022::{
+                extension::{
+                    BaseStateWithExtensionsMut, ExtensionType, StateWithExtensionsMut,
+                    transfer_hook::TransferHook,
+                },
+                state::Mint,
+            };
+            use spl_transfer_hook_interface::{
+                get_extra_account_metas_address, instruction::ExecuteInstruction,
+            };
+
+            const HOOK_PROGRAM_ID: Pubkey =
+                Pubkey::from_str_const("HookProgram11111111111111111111111111111111");
+            const EXTRA_ACCOUNT: Pubkey =
+                Pubkey::from_str_const("ExtraAccount1111111111111111111111111111111");
+
+            fn hook_mint_account() -> Account {
+                let len = ExtensionType::try_calculate_account_len::<Mint>(&[
+                    ExtensionType::TransferHook,
+                ])
+                .unwrap();
+                let mut data = vec![0; len];
+
+                let mut mint =
+                    StateWithExtensionsMut::<Mint>::unpack_uninitialized(&mut data).unwrap();
+                let hook = mint.init_extension::<TransferHook>(true).unwrap();
+                hook.program_id = OptionalNonZeroPubkey::try_from(Some(HOOK_PROGRAM_ID)).unwrap();
+                mint.base = Mint {
+                    decimals: TOKEN_A.decs_count as u8,
+                    is_initialized: true,
+                    ..Default::default()
+                };
+                mint.pack_base();
+                mint.init_account_type().unwrap();
+
+                Account {
+                    owner: TOKEN_2022_PROGRAM_ID,
+                    ..default_account_with_data(data)
+                }
+            }
+
+            /// A fixed account followed by a PDA seeded with the transfer source
+            fn validation_account() -> Account {
+                let extra_metas = [
+                    ExtraAccountMeta::new_with_pubkey(&EXTRA_ACCOUNT, false, false).unwrap(),
+                    ExtraAccountMeta::new_with_seeds(
+                        &[
+                            Seed::Literal {
+                                bytes: b"counter".to_vec(),
+                            },
+                            Seed::AccountKey { index: 0 },
+                        ],
+                        false,
+                        true,
+                    )
+                    .unwrap(),
+                ];
+
+                let mut data = vec![0; ExtraAccountMetaList::size_of(extra_metas.len()).unwrap()];
+                ExtraAccountMetaList::init::<ExecuteInstruction>(&mut data, &extra_metas).unwrap();
+
+                Account {
+                    owner: HOOK_PROGRAM_ID,
+                    ..default_account_with_data(data)
+                }
+            }
+
+            fn swap_params<'a>(
+                source_mint: Pubkey,
+                destination_mint: Pubkey,
+                source_token_account: Pubkey,
+                destination_token_account: Pubkey,
+                token_transfer_authority: Pubkey,
+                program_id: &'a Pubkey,
+            ) -> SwapParams<'a, 'a> {
+                SwapParams {
+                    swap_mode: SwapMode::ExactIn,
+                    in_amount: 1_000,
+                    out_amount: 9_000,
+                    source_mint,
+                    destination_mint,
+                    source_token_account,
+                    destination_token_account,
+                    token_transfer_authority,
+                    quote_mint_to_referrer: None,
+                    jupiter_program_id: program_id,
+                    missing_dynamic_accounts_as_default: false,
+                }
+            }
+
+            /// Market with a hooked asset mint, the validation account is only provided when
+            /// `with_validation_account` is set
+            fn init_market(with_validation_account: bool) -> Deriverse {
+                let (mut market, mut accounts_map) =
+                    test_staleness::init_market(&ClockRef::default(), MaxAge::default(), 0);
+                accounts_map.insert(market.accounts_ctx.a_mint, hook_mint_account());
+
+                if with_validation_account {
+                    accounts_map.insert(
+                        get_extra_account_metas_address(&TOKEN_A.mint, &HOOK_PROGRAM_ID),
+                        validation_account(),
+                    );
+                }
+
+                market.update(&accounts_map).unwrap();
+
+                market
+            }
+
+            #[test]
+            fn requests_validation_account() {
+                let market = init_market(false);
+
+                let validation_acc =
+                    get_extra_account_metas_address(&TOKEN_A.mint, &HOOK_PROGRAM_ID);
+                assert!(market.has_dynamic_accounts());
+                assert!(market.get_accounts_to_update().contains(&validation_acc));
+
+                let source = Pubkey::new_unique();
+                let destination = Pubkey::new_unique();
+                let err = market
+                    .get_swap_and_account_metas(&swap_params(
+                        TOKEN_A.mint,
+                        TOKEN_B.mint,
+                        source,
+                        destination,
+                        Pubkey::new_unique(),
+                        &solana_sdk::system_program::id(),
+                    ))
+                    .unwrap_err();
+                assert!(err.to_string().contains("not loaded"));
+            }
+
+            #[test]
+            fn appends_hook_accounts() {
+                let market = init_market(true);
+
+                let source = Pubkey::new_unique();
+                let destination = Pubkey::new_unique();
+                let SwapAndAccountMetas {
+                    swap,
+                    account_metas,
+                } = market
+                    .get_swap_and_account_metas(&swap_params(
+                        TOKEN_A.mint,
+                        TOKEN_B.mint,
+                        source,
+                        destination,
+                        Pubkey::new_unique(),
+                        &solana_sdk::system_program::id(),
+                    ))
+                    .unwrap();
+
+                let Swap::Deriverse {
+                    remaining_accounts_info,
+                    ..
+                } = swap
+                else {
+                    panic!("Unexpected swap {:?}", swap);
+                };
+                assert_eq!(
+                    remaining_accounts_info,
+                    Some(RemainingAccountsInfo {
+                        slices: vec![RemainingAccountsSlice {
+                            accounts_type: AccountsType::TransferHookA,
+                            length: 4,
+                        }],
+                    })
+                );
+
+                // The user sells the asset, the counter is derived from the user's account
+                let counter =
+                    Pubkey::find_program_address(&[b"counter", source.as_ref()], &HOOK_PROGRAM_ID)
+                        .0;
+                assert_eq!(
+                    account_metas[account_metas.len() - 4..],
+                    [
+                        AccountMeta::new_readonly(EXTRA_ACCOUNT, false),
+                        AccountMeta::new(counter, false),
+                        AccountMeta::new_readonly(HOOK_PROGRAM_ID, false),
+                        AccountMeta::new_readonly(
+                            get_extra_account_metas_address(&TOKEN_A.mint, &HOOK_PROGRAM_ID),
+                            false
+                        ),
+                    ]
+                );
+
+                // Buying the asset moves it out of the market vault
+                let SwapAndAccountMetas { account_metas, .. } = market
+                    .get_swap_and_account_metas(&swap_params(
+                        TOKEN_B.mint,
+                        TOKEN_A.mint,
+                        source,
+                        destination,
+                        Pubkey::new_unique(),
+                        &solana_sdk::system_program::id(),
+                    ))
+                    .unwrap();
+
+                let vault = market.a_token_state.program_address;
+                let counter =
+                    Pubkey::find_program_address(&[b"counter", vault.as_ref()], &HOOK_PROGRAM_ID).0;
+                assert_eq!(account_metas[account_metas.len() - 3].pubkey, counter);
+            }
+
+            #[test]
+            fn no_remaining_accounts_without_hooks() {
+                let (mut market, accounts_map) =
+                    test_staleness::init_market(&ClockRef::default(), MaxAge::default(), 0);
+                market.update(&accounts_map).unwrap();
+
+                let SwapAndAccountMetas { swap, .. } = market
+                    .get_swap_and_account_metas(&swap_params(
+                        TOKEN_A.mint,
+                        TOKEN_B.mint,
+                        Pubkey::new_unique(),
+                        Pubkey::new_unique(),
+                        Pubkey::new_unique(),
+                        &solana_sdk::system_program::id(),
+                    ))
+                    .unwrap();
+
+                assert!(matches!(
+                    swap,
+                    Swap::Deriverse {
+                        remaining_accounts_info: None,
+                        ..
+                    }
+                ));
+            }
+        }
+
+        pub mod test_lookup_table {
+            use super::*;
+            use crate::{
+                lookup_table::{
+                    LookupTable, MAX_EXTEND_ADDRESSES, USER_SWAP_ACCOUNTS,
+                    create_lookup_table_instructions, extend_lookup_table_instructions,
+                },
+                params::{DeriverseParams, MaxAge},
+            };
+            use jupiter_amm_interface::{SwapAndAccountMetas, SwapParams};
+            use serde_json::json;
+            use solana_sdk::address_lookup_table::{
+                self,
+                instruction::derive_lookup_table_address,
+                state::{AddressLookupTable, LookupTableMeta},
+            };
+            use std::borrow::Cow;
+
+            fn lookup_table_account(addresses: &[Pubkey]) -> Account {
+                let data = AddressLookupTable {
+                    meta: LookupTableMeta::default(),
+                    addresses: Cow::Borrowed(addresses),
+                }
+                .serialize_for_tests()
+                .unwrap();
+
+                Account {
+                    owner: address_lookup_table::program::id(),
+                    ..default_account_with_data(data)
+                }
+            }
+
+            fn swap_account_metas(market: &Deriverse) -> Vec<Pubkey> {
+                let SwapAndAccountMetas { account_metas, .. } = market
+                    .get_swap_and_account_metas(&SwapParams {
+                        swap_mode: SwapMode::ExactIn,
+                        in_amount: 1_000,
+                        out_amount: 0,
+                        source_mint: TOKEN_A.mint,
+                        destination_mint: TOKEN_B.mint,
+                        source_token_account: Pubkey::new_unique(),
+                        destination_token_account: Pubkey::new_unique(),
+                        token_transfer_authority: Pubkey::new_unique(),
+                        quote_mint_to_referrer: None,
+                        jupiter_program_id: &solana_sdk::system_program::id(),
+                        missing_dynamic_accounts_as_default: false,
+                    })
+                    .unwrap();
+
+                account_metas.into_iter().map(|meta| meta.pubkey).collect()
+            }
+
+            /// Updated market configured with `lookup_table`, the table account holds
+            /// `addresses` when provided
+            fn init_market(lookup_table: Pubkey, addresses: Option<&[Pubkey]>) -> Deriverse {
+                let (mut market, mut accounts_map) =
+                    test_staleness::init_market(&ClockRef::default(), MaxAge::default(), 0);
+                market.params = DeriverseParams {
+                    lookup_table: Some(lookup_table),
+                    ..Default::default()
+                };
+
+                if let Some(addresses) = addresses {
+                    accounts_map.insert(lookup_table, lookup_table_account(addresses));
+                }
+
+                market.update(&accounts_map).unwrap();
+
+                market
+            }
+
+            #[test]
+            fn parses_lookup_table_param() {
+                let lookup_table = Pubkey::new_unique();
+
+                let params = DeriverseParams::from_value(Some(&json!({
+                    "lookupTable": lookup_table.to_string(),
+                })))
+                .unwrap();
+                assert_eq!(params.lookup_table, Some(lookup_table));
+
+                assert!(
+                    DeriverseParams::from_value(Some(&json!({ "lookupTable": "not a key" })))
+                        .is_err()
+                );
+            }
+
+            #[test]
+            fn static_accounts_cover_swap() {
+                let market = init_market(Pubkey::new_unique(), None);
+
+                let static_accounts = market.lookup_table_accounts();
+                let swap_accounts = swap_account_metas(&market);
+
+                let mut unique_accounts = static_accounts.clone();
+                unique_accounts.sort();
+                unique_accounts.dedup();
+                assert_eq!(unique_accounts.len(), static_accounts.len());
+
+                let user_accounts = swap_accounts
+                    .iter()
+                    .filter(|account| !static_accounts.contains(account))
+                    .count();
+                assert_eq!(user_accounts, USER_SWAP_ACCOUNTS);
+
+                assert!(
+                    static_accounts
+                        .iter()
+                        .all(|account| swap_accounts.contains(account))
+                );
+            }
+
+            #[test]
+            fn accounts_len_without_table() {
+                let lookup_table = Pubkey::new_unique();
+                let market = init_market(lookup_table, None);
+
+                assert_eq!(market.params.lookup_table, Some(lookup_table));
+                assert!(market.get_accounts_to_update().contains(&lookup_table));
+
+                let mut swap_accounts = swap_account_metas(&market);
+                swap_accounts.sort();
+                swap_accounts.dedup();
+
+                assert_eq!(market.get_accounts_len(), swap_accounts.len());
+            }
+
+            #[test]
+            fn accounts_len_with_table() {
+                let static_accounts =
+                    init_market(Pubkey::new_unique(), None).lookup_table_accounts();
+
+                let market = init_market(Pubkey::new_unique(), Some(&static_accounts));
+                assert_eq!(market.get_accounts_len(), USER_SWAP_ACCOUNTS);
+
+                // Accounts missing from a partial table still count
+                let market = init_market(Pubkey::new_unique(), Some(&static_accounts[..20]));
+                assert_eq!(
+                    market.get_accounts_len(),
+                    USER_SWAP_ACCOUNTS + static_accounts.len() - 20
+                );
+            }
+
+            #[test]
+            fn builds_and_extends_table() {
+                let authority = Pubkey::new_unique();
+                let payer = Pubkey::new_unique();
+                let accounts: Vec<Pubkey> = (0..MAX_EXTEND_ADDRESSES + 5)
+                    .map(|_| Pubkey::new_unique())
+                    .collect();
+
+                let (address, instructions) =
+                    create_lookup_table_instructions(&authority, &payer, 42, &accounts);
+
+                assert_eq!(address, derive_lookup_table_address(&authority, 42).0);
+                // Create followed by two extensions
+                assert_eq!(instructions.len(), 3);
+                assert!(
+                    instructions
+                        .iter()
+                        .all(|ix| ix.program_id == address_lookup_table::program::id())
+                );
+
+                let table =
+                    LookupTable::new(&address, &lookup_table_account(&accounts[..10])).unwrap();
+                assert_eq!(table.missing(&accounts), accounts[10..].to_vec());
+                assert_eq!(
+                    extend_lookup_table_instructions(&table, &authority, &payer, &accounts).len(),
+                    1
+                );
+
+                let table = LookupTable::new(&address, &lookup_table_account(&accounts)).unwrap();
+                assert!(
+                    extend_lookup_table_instructions(&table, &authority, &payer, &accounts)
+                        .is_empty()
+                );
+            }
+
+            #[test]
+            fn rejects_foreign_table() {
+                let address = Pubkey::new_unique();
+
+                let err = LookupTable::new(
+                    &address,
+                    &default_account_with_data(lookup_table_account(&[]).data),
+                )
+                .unwrap_err();
+                assert!(err.to_string().contains("is owned by"));
+            }
+        }
+
+        pub mod test_discovery {
+            use super::*;
+            use crate::{
+                discovery::{discover_instruments, instrument_header},
+                helper::Helper,
+            };
+
+            fn instrument(asset: &Token, crncy: &Token) -> (Pubkey, Account) {
+                let header = InstrAccountHeader {
+                    asset_mint: asset.mint,
+                    crncy_mint: crncy.mint,
+                    asset_token_id: asset.token_id,
+                    crncy_token_id: crncy.token_id,
+                    ..Zeroable::zeroed()
+                };
+
+                (
+                    Pubkey::new_spot_acc(INSTR, asset.token_id, crncy.token_id),
+                    program_account(INSTR, bytes_of(&header).to_vec()),
+                )
+            }
+
+            const TOKEN_C: Token = Token {
+                mint: Pubkey::from_str_const("CTokenMint111111111111111111111111111111111"),
+                token_id: 1,
+                decs_count: 8,
+            };
+
+            #[test]
+            fn lists_instruments_of_dump() {
+                let (ab_key, ab_acc) = instrument(&TOKEN_A, &TOKEN_B);
+                let (cb_key, cb_acc) = instrument(&TOKEN_C, &TOKEN_B);
+
+                let dump = vec![
+                    (ab_key, ab_acc.clone()),
+                    (TOKEN_A.mint.new_token_acc(), token_state_account(&TOKEN_A)),
+                    (cb_key, cb_acc),
+                    // Header copied to an address it doesn't derive to
+                    (Pubkey::new_unique(), ab_acc.clone()),
+                    // Header not owned by the program
+                    (
+                        Pubkey::new_spot_acc(INSTR, 5, 6),
+                        default_account_with_data(ab_acc.data.clone()),
+                    ),
+                ];
+
+                let instruments = discover_instruments(dump);
+
+                assert_eq!(
+                    instruments
+                        .iter()
+                        .map(|keyed_account| keyed_account.key)
+                        .collect::<Vec<_>>(),
+                    vec![cb_key, ab_key]
+                );
+
+                let market = Deriverse::from_keyed_account(
+                    &instruments[1],
+                    &AmmContext {
+                        clock_ref: ClockRef::default(),
+                    },
+                )
+                .unwrap();
+                assert_eq!(market.key(), ab_key);
+
+                let accounts_to_update = market.get_accounts_to_update();
+                assert!(accounts_to_update.contains(&TOKEN_A.mint));
+                assert!(accounts_to_update.contains(&TOKEN_B.mint));
+            }
+
+            #[test]
+            fn rejects_short_header() {
+                let (key, mut account) = instrument(&TOKEN_A, &TOKEN_B);
+                assert!(instrument_header(&key, &account).is_some());
+
+                account.data.pop();
+                assert!(instrument_header(&key, &account).is_none());
+            }
+
+            #[test]
+            fn accepts_longer_header() {
+                let (key, mut account) = instrument(&TOKEN_A, &TOKEN_B);
+                account.data.extend_from_slice(&[0; 64]);
+
+                let instruments = discover_instruments(vec![(key, account)]);
+                assert_eq!(instruments.len(), 1);
+                assert_eq!(instruments[0].key, key);
+            }
+        }
+
+        pub mod test_pair_resolver {
+            use super::*;
+            use crate::{helper::Helper, resolver::PairResolver};
+
+            /// Token states of both mints and the instrument trading them
+            fn init_accounts() -> AccountMap {
+                let mut accounts_map = AccountMap::with_hasher(ahash::RandomState::new());
+
+                accounts_map.insert(TOKEN_A.mint.new_token_acc(), token_state_account(&TOKEN_A));
+                accounts_map.insert(TOKEN_B.mint.new_token_acc(), token_state_account(&TOKEN_B));
+
+                let header = InstrAccountHeader {
+                    asset_mint: TOKEN_A.mint,
+                    crncy_mint: TOKEN_B.mint,
+                    asset_token_id: TOKEN_A.token_id,
+                    crncy_token_id: TOKEN_B.token_id,
+                    ..Zeroable::zeroed()
+                };
+                accounts_map.insert(
+                    Pubkey::new_spot_acc(INSTR, TOKEN_A.token_id, TOKEN_B.token_id),
+                    program_account(INSTR, bytes_of(&header).to_vec()),
+                );
+
+                accounts_map
+            }
+
+            #[test]
+            fn resolves_either_order() {
+                let accounts_map = init_accounts();
+                let mut resolver = PairResolver::new();
+
+                let pair = resolver
+                    .resolve(&accounts_map, &TOKEN_A.mint, &TOKEN_B.mint)
+                    .unwrap()
+                    .unwrap();
+                let reversed = resolver
+                    .resolve(&accounts_map, &TOKEN_B.mint, &TOKEN_A.mint)
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(pair, reversed);
+                assert_eq!(
+                    pair.key,
+                    Pubkey::new_spot_acc(INSTR, TOKEN_A.token_id, TOKEN_B.token_id)
+                );
+                assert!(pair.is_asset(&TOKEN_A.mint));
+                assert_eq!(pair.crncy_mint, TOKEN_B.mint);
+            }
+
+            #[test]
+            fn caches_token_ids() {
+                let mut accounts_map = init_accounts();
+                let mut resolver = PairResolver::new();
+
+                resolver
+                    .resolve(&accounts_map, &TOKEN_B.mint, &TOKEN_A.mint)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(resolver.token_id(&TOKEN_A.mint), Some(TOKEN_A.token_id));
+
+                // Token states are no longer read once the ids are known
+                accounts_map.remove(&TOKEN_A.mint.new_token_acc());
+                accounts_map.remove(&TOKEN_B.mint.new_token_acc());
+
+                let pair = resolver
+                    .resolve(&accounts_map, &TOKEN_B.mint, &TOKEN_A.mint)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(pair.asset_mint, TOKEN_A.mint);
+            }
+
+            #[test]
+            fn unknown_pairs() {
+                let mut accounts_map = init_accounts();
+                let mut resolver = PairResolver::new();
+
+                // Mint without token state
+                let unlisted = Pubkey::new_unique();
+                assert_eq!(
+                    resolver
+                        .resolve(&accounts_map, &TOKEN_A.mint, &unlisted)
+                        .unwrap(),
+                    None
+                );
+                assert_eq!(resolver.token_id(&unlisted), None);
+
+                // Listed mints without instrument
+                accounts_map.remove(&Pubkey::new_spot_acc(
+                    INSTR,
+                    TOKEN_A.token_id,
+                    TOKEN_B.token_id,
+                ));
+                assert_eq!(
+                    resolver
+                        .resolve(&accounts_map, &TOKEN_A.mint, &TOKEN_B.mint)
+                        .unwrap(),
+                    None
+                );
+
+                assert!(
+                    resolver
+                        .resolve(&accounts_map, &TOKEN_A.mint, &TOKEN_A.mint)
+                        .is_err()
+                );
+            }
+        }
+
+        pub mod test_exact_out_buy {
+            use super::*;
+            use crate::amm::DeriverseAmm;
+            use drv_models::state::types::OrderSide;
+
+            /// Book of `test_quote_order_book_only` with an extra ask at a price that isn't a
+            /// multiple of the dec factor, next to an AMM holding `a_tokens` and `b_tokens`
+            fn init_deriverse(a_tokens: i64, b_tokens: i64) -> Deriverse {
+                let mut deriverse = test_quote_order_book_only::init_deriverse();
+
+                deriverse
+                    .order_book
+                    .insert(OrderSide::Ask, 10_012_345_678, 50_000)
+                    .unwrap();
+
+                deriverse.amm = DeriverseAmm {
+                    k: a_tokens as i128 * b_tokens as i128,
+                    a_tokens,
+                    b_tokens,
+                    dec_factor: deriverse.amm.dec_factor,
+                };
+
+                deriverse
+            }
+
+            fn buy(amount: u64, swap_mode: SwapMode) -> QuoteParams {
+                QuoteParams {
+                    amount,
+                    input_mint: TOKEN_B.mint,
+                    output_mint: TOKEN_A.mint,
+                    swap_mode,
+                }
+            }
+
+            fn assert_input_covers_output(deriverse: &Deriverse) {
+                for requested in (1..=30).map(|i| i * 10_007) {
+                    let exact_out = deriverse
+                        .quote(&buy(requested, SwapMode::ExactOut))
+                        .unwrap();
+                    assert_eq!(exact_out.out_amount, requested);
+
+                    let exact_in = deriverse
+                        .quote(&buy(exact_out.in_amount, SwapMode::ExactIn))
+                        .unwrap();
+
+                    assert!(
+                        exact_in.out_amount >= requested,
+                        "{} paid for {} buys {}",
+                        exact_out.in_amount,
+                        requested,
+                        exact_in.out_amount
+                    );
+                }
+            }
+
+            #[test]
+            fn input_covers_output_on_lines() {
+                assert_input_covers_output(&init_deriverse(0, 0));
+            }
+
+            #[test]
+            fn input_covers_output_on_lines_and_amm() {
+                let deriverse = init_deriverse(
+                    get_dec_factor(TOKEN_A.decs_count as u8),
+                    10 * get_dec_factor(TOKEN_B.decs_count as u8),
+                );
+
+                let legs = deriverse
+                    .quote_detailed(&buy(200_000, SwapMode::ExactOut), None)
+                    .unwrap()
+                    .legs;
+                assert!(legs.iter().any(|leg| leg.source == FillSource::Amm));
+                assert!(legs.iter().any(|leg| leg.source != FillSource::Amm));
+
+                assert_input_covers_output(&deriverse);
+            }
+
+            #[test]
+            fn input_covers_output_with_fees() {
+                let mut deriverse = init_deriverse(
+                    get_dec_factor(TOKEN_A.decs_count as u8),
+                    10 * get_dec_factor(TOKEN_B.decs_count as u8),
+                );
+                deriverse.fee_rate_factor = 1.0;
+
+                // 0.1 makes the ExactIn budget of exact multiples land a unit short in f64
+                for fee_rate in [0.0007, 0.0025, 0.1] {
+                    deriverse.instr_header.day_volatility = fee_rate;
+
+                    let quote = deriverse.quote(&buy(100_070, SwapMode::ExactOut)).unwrap();
+                    assert!(quote.fee_amount > 0);
+
+                    assert_input_covers_output(&deriverse);
+                }
+            }
+        }
+    }
+
+    pub mod rpc_tests {
+
+        use ahash::{HashMap, HashMapExt};
+        use bytemuck::bytes_of;
+        use drv_models::state::{token::TokenState, types::account_type::INSTR};
+        use jupiter_amm_interface::{
+            Amm, AmmContext, ClockRef, KeyedAccount, SwapAndAccountMetas, SwapParams,
+        };
+        use once_cell::sync::Lazy;
+        use solana_client::rpc_client::RpcClient;
+        use solana_sdk::{
+            commitment_config::CommitmentConfig,
+            instruction::Instruction,
+            pubkey::Pubkey,
+            signature::Keypair,
+            signer::{EncodableKey, Signer},
+            transaction::Transaction,
+        };
+        use spl_associated_token_account::get_associated_token_address_with_program_id;
+
+        use crate::{
+            Deriverse,
+            custom_sdk::{
+                deposit::{DepositBuildContext, DepositContext},
+                new_spot_order::{NewSpotOrderBuildContext, NewSpotOrderContext},
+                traits::{Context, InstructionBuilder},
+            },
+            from_swap,
+            helper::{Helper, get_dec_factor},
+            portfolio::{ClientAccounts, ClientPrimary},
+            program_id,
+            tests::tests::rpc_tests::config::{TOKEN_A, TOKEN_B},
+        };
+
+        static RPC: Lazy<RpcClient> = Lazy::new(|| {
+            let url = "https://api.devnet.solana.com";
+
+            RpcClient::new_with_commitment(url, CommitmentConfig::confirmed())
         });
 
         static CLIENT_A: Lazy<Keypair> =
@@ -945,6 +4327,18 @@ pub mod tests {
             assert!(current_slot > 0);
         }
 
+        #[cfg(feature = "rpc")]
+        #[test]
+        fn discover_instruments() {
+            let instruments = crate::discovery::fetch_instruments(&RPC).unwrap();
+
+            assert!(
+                instruments
+                    .iter()
+                    .any(|keyed_account| keyed_account.key == build_key_account().key)
+            );
+        }
+
         #[test]
         fn instruction_builder() {
             let ix = RPC
@@ -969,9 +4363,8 @@ pub mod tests {
             );
 
             let client_primary = {
-                let addr = CLIENT_A.pubkey().new_client_primary_acc();
-                let acc = RPC.get_account(&addr).unwrap();
-                unsafe { *(acc.data.as_ptr() as *const ClientPrimaryAccountHeader) }
+                let addr = ClientAccounts::new(&CLIENT_A.pubkey()).primary;
+                ClientPrimary::new(&RPC.get_account(&addr).unwrap()).unwrap()
             };
 
             println!("Client primary: {}", client_primary.id);
@@ -0,0 +1,66 @@
+//! Token-2022 `TransferFeeConfig` support. Tokens sent to or from the market lose the
+//! transfer fee of their mint on the way, quotes are adjusted so they describe what leaves
+//! and lands in the user's accounts.
+
+use anyhow::{Result, anyhow};
+use solana_sdk::account::Account;
+use spl_token_2022::{
+    extension::{BaseStateWithExtensions, StateWithExtensions, transfer_fee::TransferFeeConfig},
+    state::Mint,
+};
+
+use crate::accounts::TOKEN_2022_PROGRAM_ID;
+
+/// Transfer fee schedule of a mint, empty for legacy mints and Token-2022 mints without the
+/// extension
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TransferFees(Option<TransferFeeConfig>);
+
+impl TransferFees {
+    pub fn new(mint_acc: &Account) -> Result<Self> {
+        if mint_acc.owner != TOKEN_2022_PROGRAM_ID {
+            return Ok(TransferFees(None));
+        }
+
+        let mint = StateWithExtensions::<Mint>::unpack(&mint_acc.data)
+            .map_err(|err| anyhow!("Invalid Token-2022 mint: {}", err))?;
+
+        Ok(TransferFees(
+            mint.get_extension::<TransferFeeConfig>().ok().copied(),
+        ))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// Fee withheld when `amount` is sent during `epoch`
+    pub fn fee(&self, epoch: u64, amount: u64) -> Result<u64> {
+        let Some(config) = &self.0 else {
+            return Ok(0);
+        };
+
+        config
+            .calculate_epoch_fee(epoch, amount)
+            .ok_or(anyhow!("Arithmetic Overflow"))
+    }
+
+    /// Amount landing when `amount` is sent
+    pub fn net(&self, epoch: u64, amount: u64) -> Result<u64> {
+        Ok(amount.saturating_sub(self.fee(epoch, amount)?))
+    }
+
+    /// Amount to send so that `net` lands
+    pub fn gross(&self, epoch: u64, net: u64) -> Result<u64> {
+        let Some(config) = &self.0 else {
+            return Ok(net);
+        };
+
+        let fee = config
+            .get_epoch_fee(epoch)
+            .calculate_inverse_fee(net)
+            .ok_or(anyhow!("Arithmetic Overflow"))?;
+
+        net.checked_add(fee).ok_or(anyhow!("Arithmetic Overflow"))
+    }
+}
@@ -0,0 +1,155 @@
+//! Token-2022 `TransferHook` support. The token program invokes the hook program on every
+//! transfer of the mint, so the swap has to carry the hook's extra accounts, resolved the way
+//! `spl_transfer_hook_interface::offchain::add_extra_account_metas_for_execute` does.
+
+use anyhow::{Result, anyhow};
+use jupiter_amm_interface::AccountMap;
+use solana_sdk::{account::Account, instruction::AccountMeta, pubkey::Pubkey};
+use spl_tlv_account_resolution::{account::ExtraAccountMeta, state::ExtraAccountMetaList};
+use spl_token_2022::{
+    extension::{StateWithExtensions, transfer_hook},
+    state::Mint,
+};
+use spl_transfer_hook_interface::{
+    get_extra_account_metas_address,
+    instruction::{ExecuteInstruction, TransferHookInstruction},
+};
+use spl_type_length_value::state::TlvStateBorrowed;
+
+use crate::accounts::TOKEN_2022_PROGRAM_ID;
+
+/// Accounts the token program passes to `Execute` ahead of the extra ones
+const EXECUTE_ACCOUNTS_LEN: usize = 5;
+
+/// Hook program of a mint with the extra account metas stored in its validation account
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferHook {
+    pub mint: Pubkey,
+    pub program_id: Pubkey,
+    /// `ExtraAccountMetaList` PDA of the mint
+    pub validation_account: Pubkey,
+    /// `None` until the validation account was loaded
+    extra_metas: Option<Vec<ExtraAccountMeta>>,
+}
+
+/// Single token transfer performed by the swap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HookTransfer {
+    pub source: Pubkey,
+    pub destination: Pubkey,
+    pub authority: Pubkey,
+    pub amount: u64,
+}
+
+impl TransferHook {
+    /// `None` when the mint has no hook program set
+    pub fn new(mint: &Pubkey, mint_acc: &Account) -> Result<Option<Self>> {
+        if mint_acc.owner != TOKEN_2022_PROGRAM_ID {
+            return Ok(None);
+        }
+
+        let state = StateWithExtensions::<Mint>::unpack(&mint_acc.data)
+            .map_err(|err| anyhow!("Invalid Token-2022 mint: {}", err))?;
+
+        let Some(program_id) = transfer_hook::get_program_id(&state) else {
+            return Ok(None);
+        };
+
+        Ok(Some(TransferHook {
+            mint: *mint,
+            program_id,
+            validation_account: get_extra_account_metas_address(mint, &program_id),
+            extra_metas: None,
+        }))
+    }
+
+    /// Hook of the mint, loaded when `account_map` already holds its validation account
+    pub fn from_accounts(
+        mint: &Pubkey,
+        mint_acc: &Account,
+        account_map: &AccountMap,
+    ) -> Result<Option<Self>> {
+        let Some(mut hook) = Self::new(mint, mint_acc)? else {
+            return Ok(None);
+        };
+
+        if let Some(validation_acc) = account_map.get(&hook.validation_account) {
+            hook.load(validation_acc)?;
+        }
+
+        Ok(Some(hook))
+    }
+
+    /// Decodes the `Execute` extra account metas from the validation account
+    pub fn load(&mut self, validation_acc: &Account) -> Result<()> {
+        let tlv_state = TlvStateBorrowed::unpack(&validation_acc.data)
+            .map_err(|err| anyhow!("Invalid extra account metas: {}", err))?;
+        let extra_metas =
+            ExtraAccountMetaList::unpack_with_tlv_state::<ExecuteInstruction>(&tlv_state)
+                .map_err(|err| anyhow!("Invalid extra account metas: {}", err))?;
+
+        self.extra_metas = Some(extra_metas.data().to_vec());
+
+        Ok(())
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.extra_metas.is_some()
+    }
+
+    /// Extra accounts the hook adds to a transfer, 0 until loaded
+    pub fn extra_accounts_len(&self) -> usize {
+        self.extra_metas.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Extra accounts of `transfer` followed by the hook program and its validation account
+    pub fn account_metas(&self, transfer: &HookTransfer) -> Result<Vec<AccountMeta>> {
+        let extra_metas = self.extra_metas.as_ref().ok_or(anyhow!(
+            "Transfer hook accounts of mint {} are not loaded",
+            self.mint
+        ))?;
+
+        let instruction_data = TransferHookInstruction::Execute {
+            amount: transfer.amount,
+        }
+        .pack();
+
+        // Seeds refer to accounts by their index in the `Execute` instruction
+        let mut keys = vec![
+            transfer.source,
+            self.mint,
+            transfer.destination,
+            transfer.authority,
+            self.validation_account,
+        ];
+
+        let mut account_metas = Vec::with_capacity(extra_metas.len() + 2);
+
+        for extra_meta in extra_metas {
+            let pubkey = extra_meta
+                .resolve(&instruction_data, &self.program_id, |idx| {
+                    keys.get(idx).map(|key| (key, None))
+                })
+                .map_err(|err| {
+                    anyhow!(
+                        "Unresolved transfer hook account {} of mint {}: {}",
+                        keys.len() - EXECUTE_ACCOUNTS_LEN,
+                        self.mint,
+                        err
+                    )
+                })?;
+
+            keys.push(pubkey);
+            account_metas.push(AccountMeta {
+                pubkey,
+                is_signer: extra_meta.is_signer.into(),
+                is_writable: extra_meta.is_writable.into(),
+            });
+        }
+
+        account_metas.push(AccountMeta::new_readonly(self.program_id, false));
+        account_metas.push(AccountMeta::new_readonly(self.validation_account, false));
+
+        Ok(account_metas)
+    }
+}